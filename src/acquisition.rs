@@ -0,0 +1,132 @@
+// External crate imports
+use anyhow::Result;
+use crossbeam::channel::{Receiver, Sender, TryRecvError};
+use serde_json::{json, Value};
+
+// Standard library imports
+use std::sync::Arc;
+
+// Current crate and supermodule imports
+use crate::backend::CameraBackend;
+use crate::control_api::{ControlCommand, ControlRequest};
+use crate::structs::{ImageData, MessageType};
+
+/// Grab frames from `camera` until a "kill" message arrives or `messages`
+/// is disconnected, attaching any pending message to the next frame handed
+/// to the frame handler.
+///
+/// Parameter updates and parameter requests from the control socket are
+/// handled between frames; other control requests addressed to
+/// `camera_serial` go on to the frame handler, as do framerate changes.
+pub fn acquisition_loop(
+    camera: &mut dyn CameraBackend,
+    camera_serial: &str,
+    messages: &Receiver<MessageType>,
+    frames: &Sender<(Arc<ImageData>, MessageType)>,
+) -> Result<()> {
+    camera.start_acquisition()?;
+    log::info!("Starting acquisition");
+
+    loop {
+        let message = match messages.try_recv() {
+            Ok(message) => message,
+            Err(TryRecvError::Empty) => MessageType::Empty,
+            Err(TryRecvError::Disconnected) => {
+                log::warn!("Message channel closed, stopping acquisition");
+                MessageType::Kill
+            }
+        };
+
+        // check if got "kill" in message
+        if message.is_kill() {
+            break;
+        }
+        let message = match message {
+            MessageType::ParameterUpdate { name, value, .. } => {
+                match camera.set_parameter(&name, &value) {
+                    Ok(()) => {
+                        log::info!("Set {} to {}", name, value);
+                        framerate_change(camera, &name)
+                    }
+                    Err(e) => {
+                        log::error!("Failed to set {} to {}: {:#}", name, value, e);
+                        MessageType::Empty
+                    }
+                }
+            }
+            MessageType::Command(request) if request.addresses(camera_serial) => {
+                handle_command(camera, camera_serial, request)
+            }
+            MessageType::Command(_) => MessageType::Empty,
+            message => message,
+        };
+
+        // Get frame from camera
+        let image_data = Arc::new(camera.next_frame()?);
+
+        // send frame with the incoming message
+        match frames.send((image_data, message)) {
+            Ok(_) => {
+                log::trace!("Sent frame to frame handler");
+            }
+            Err(_e) => {
+                log::warn!("Failed to send frame to frame handler");
+            }
+        }
+    }
+
+    camera.stop_acquisition()
+}
+
+fn is_framerate(name: &str) -> bool {
+    matches!(name, "framerate" | "fps")
+}
+
+/// Tell the frame handler the framerate the camera applied after `name`
+/// was set, so clips keep their length in seconds.
+fn framerate_change(camera: &mut dyn CameraBackend, name: &str) -> MessageType {
+    if !is_framerate(name) {
+        return MessageType::Empty;
+    }
+    match camera.get_parameter(name) {
+        Ok(fps) => MessageType::FramerateChanged(fps),
+        Err(e) => {
+            log::error!("Failed to read back the framerate: {:#}", e);
+            MessageType::Empty
+        }
+    }
+}
+
+/// Answer the parameter commands, which need the camera, and hand
+/// everything else back to be forwarded.
+fn handle_command(
+    camera: &mut dyn CameraBackend,
+    camera_serial: &str,
+    request: ControlRequest,
+) -> MessageType {
+    let mut forwarded = MessageType::Empty;
+    let result = match &request.command {
+        ControlCommand::SetParam { name, value } => {
+            let value = match value {
+                Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            camera
+                .set_parameter(name, &value)
+                .and_then(|()| camera.get_parameter(name))
+                .map(|applied| {
+                    log::info!("Set {} to {}", name, applied);
+                    if is_framerate(name) {
+                        forwarded = MessageType::FramerateChanged(applied);
+                    }
+                    json!({ "name": name, "value": applied })
+                })
+        }
+        ControlCommand::GetParam { name } => camera
+            .get_parameter(name)
+            .map(|value| json!({ "name": name, "value": value })),
+        _ => return MessageType::Command(request),
+    };
+    request.answer(camera_serial, result);
+    forwarded
+}
@@ -0,0 +1,107 @@
+// External crate imports
+use anyhow::{anyhow, Context, Result};
+use image::{ImageBuffer, Luma, Rgb};
+
+// Standard library imports
+use std::collections::VecDeque;
+use std::fs::{create_dir_all, File};
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::Arc;
+
+// Current crate imports
+use crate::structs::ImageData;
+
+/// Save a single frame, in the format given by the extension of `path`.
+/// Deeper formats keep their raw values as 16-bit gray, Bayer frames are
+/// saved as the 8-bit mosaic.
+pub fn save_image(image: &ImageData, path: &Path) -> Result<()> {
+    let (width, height) = (image.width, image.height);
+    let size_mismatch = || anyhow!("Frame data does not match its size {}x{}", width, height);
+
+    match image.pixel_format.bytes_per_pixel() {
+        2 => {
+            let pixels = image
+                .data
+                .chunks_exact(2)
+                .map(|bytes| u16::from_le_bytes([bytes[0], bytes[1]]))
+                .collect();
+            ImageBuffer::<Luma<u16>, Vec<u16>>::from_raw(width, height, pixels)
+                .ok_or_else(size_mismatch)?
+                .save(path)?
+        }
+        3 => {
+            // frames are stored in blue, green, red order
+            let pixels = image
+                .data
+                .chunks_exact(3)
+                .flat_map(|bgr| [bgr[2], bgr[1], bgr[0]])
+                .collect();
+            ImageBuffer::<Rgb<u8>, Vec<u8>>::from_raw(width, height, pixels)
+                .ok_or_else(size_mismatch)?
+                .save(path)?
+        }
+        _ => ImageBuffer::<Luma<u8>, Vec<u8>>::from_raw(width, height, image.data.clone())
+            .ok_or_else(size_mismatch)?
+            .save(path)?,
+    }
+    Ok(())
+}
+
+/// Save every frame to the directory `dir` as `000000.<extension>`,
+/// `000001.<extension>`, ... in the order of the clip's CSV.
+pub fn write_sequence(
+    images: &VecDeque<Arc<ImageData>>,
+    dir: &Path,
+    extension: &str,
+) -> Result<()> {
+    create_dir_all(dir).context("Failed to create frame directory")?;
+    for (i, image) in images.iter().enumerate() {
+        let path = dir.join(format!("{:06}.{}", i, extension));
+        save_image(image, &path)
+            .with_context(|| format!("Failed to save frame {}", path.display()))?;
+    }
+    Ok(())
+}
+
+/// Write the frames back to back to `path` exactly as received, and where
+/// each one starts to the CSV `index_path`.
+///
+/// The index names the layout of each frame with its ffmpeg pixel format,
+/// e.g. `gray16le` for little-endian 16-bit words.
+pub fn write_raw(images: &VecDeque<Arc<ImageData>>, path: &Path, index_path: &Path) -> Result<()> {
+    let mut data = BufWriter::new(
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?,
+    );
+    let mut index = BufWriter::new(
+        File::create(index_path)
+            .with_context(|| format!("Failed to create {}", index_path.display()))?,
+    );
+
+    writeln!(
+        index,
+        "nframe,timestamp_raw,offset,bytes,width,height,pixel_format"
+    )?;
+    let mut offset = 0;
+    for image in images {
+        data.write_all(&image.data)
+            .with_context(|| format!("Failed to write {}", path.display()))?;
+        writeln!(
+            index,
+            "{},{},{},{},{},{},{}",
+            image.nframe,
+            image.timestamp_raw,
+            offset,
+            image.data.len(),
+            image.width,
+            image.height,
+            image.pixel_format.ffmpeg_pix_fmt(),
+        )?;
+        offset += image.data.len() as u64;
+    }
+
+    data.flush()
+        .with_context(|| format!("Failed to write {}", path.display()))?;
+    index.flush()?;
+    Ok(())
+}
@@ -0,0 +1,30 @@
+// External crate imports
+use anyhow::Result;
+
+// Current crate and supermodule imports
+use crate::{settings::AppliedCameraSettings, structs::ImageData, ximea_camera::CameraConfig};
+
+/// Common interface over the physical XIMEA camera and the simulated one,
+/// so the acquisition loop does not care where frames come from.
+pub trait CameraBackend {
+    /// Apply `config` to the device. Called before acquisition starts.
+    fn configure(&mut self, config: &CameraConfig) -> Result<()>;
+
+    fn start_acquisition(&mut self) -> Result<()>;
+
+    fn stop_acquisition(&mut self) -> Result<()>;
+
+    /// Change a single setting by name while the camera is running, e.g.
+    /// `exposure` (µs), `gain` (dB) or `framerate`.
+    fn set_parameter(&mut self, name: &str, value: &str) -> Result<()>;
+
+    /// Read the current value of a setting accepted by `set_parameter`.
+    fn get_parameter(&mut self, name: &str) -> Result<f32>;
+
+    /// Read back the settings the device is actually using.
+    fn applied_settings(&mut self) -> Result<AppliedCameraSettings>;
+
+    /// Block until the next frame is available and return it together with
+    /// its `nframe`, `acq_nframe`, `timestamp_raw` and `exposure_time`.
+    fn next_frame(&mut self) -> Result<ImageData>;
+}
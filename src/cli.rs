@@ -1,48 +1,528 @@
 // External crate imports, alphabetized
-use clap::Parser;
+use clap::{Parser, Subcommand};
+
+// Standard library imports
+use std::path::PathBuf;
+
+// Library crate imports
+use ximea_camera::config::{
+    AppConfig, ClipFormat, ConfigFormat, OverlapPolicy, TopicConfig, Transport,
+};
+use ximea_camera::encoders::EncoderProfile;
+use ximea_camera::simulated::{FramePattern, SimulationConfig};
+use ximea_camera::structs::PixelFormat;
+use ximea_camera::templates::PathTemplate;
+use ximea_camera::ximea_camera::{
+    BinningMode, CameraConfig, ExposureMode, StrobeConfig, TriggerMode,
+};
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExposureModeArg {
+    /// Fixed exposure from --exposure
+    Manual,
+    /// Auto exposure/auto gain within the --ae-* limits
+    Auto,
+}
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
-pub struct Args {
-    #[arg(long, default_value_t = 0)]
-    pub serial: u32,
+pub struct Cli {
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// parsed once at startup, the size difference does not matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Acquire frames and save clips around incoming triggers
+    Record(RecordArgs),
+
+    /// Print every attached camera with its capabilities
+    ListCameras,
+
+    /// Print the configuration `record` would run with after merging the
+    /// config file and command-line flags
+    PrintConfig(PrintConfigArgs),
+}
+
+#[derive(clap::Args, Debug)]
+pub struct PrintConfigArgs {
+    #[command(flatten)]
+    pub record: RecordArgs,
+
+    #[arg(long, value_enum, default_value_t = ConfigFormat::Toml)]
+    pub format: ConfigFormat,
+}
+
+/// Every setting except `--config` and `--debug` overrides the value from the
+/// config file; the defaults below apply when neither sets it.
+#[derive(clap::Args, Debug)]
+pub struct RecordArgs {
+    /// TOML, YAML or JSON file with camera, recording, messaging and output
+    /// sections
+    #[arg(long)]
+    pub config: Option<PathBuf>,
+
+    /// Camera serial number, repeat or comma-separate to record from several
+    /// cameras at once; defaults to the first attached camera
+    #[arg(long, value_delimiter = ',')]
+    pub serial: Vec<String>,
+
+    /// Framerate [default: 500]
+    #[arg(long)]
+    pub fps: Option<f32>,
+
+    /// Exposure time in µs, used with --exposure-mode manual [default: 2000]
+    #[arg(long)]
+    pub exposure: Option<f32>,
+
+    /// [default: manual]
+    #[arg(long, value_enum)]
+    pub exposure_mode: Option<ExposureModeArg>,
+
+    /// Longest exposure auto exposure may choose, in µs [default: 2000]
+    #[arg(long)]
+    pub ae_max_exposure: Option<f32>,
+
+    /// Highest gain auto gain may choose, in dB [default: 12]
+    #[arg(long)]
+    pub ae_max_gain: Option<f32>,
+
+    /// Target average intensity for auto exposure, in percent [default: 75]
+    #[arg(long)]
+    pub ae_target_level: Option<f32>,
+
+    /// Share of the auto correction done with exposure rather than gain (0-1)
+    /// [default: 1]
+    #[arg(long)]
+    pub ae_priority: Option<f32>,
+
+    /// [default: mono8]
+    #[arg(long, value_enum)]
+    pub pixel_format: Option<PixelFormat>,
+
+    /// ROI width [default: 2016]
+    #[arg(long)]
+    pub width: Option<u32>,
+
+    /// ROI height [default: 2016]
+    #[arg(long)]
+    pub height: Option<u32>,
+
+    /// [default: 1056]
+    #[arg(long)]
+    pub offset_x: Option<u32>,
+
+    /// [default: 170]
+    #[arg(long)]
+    pub offset_y: Option<u32>,
+
+    /// Center the ROI on the sensor instead of using --offset-x/--offset-y
+    #[arg(long, default_value_t = false)]
+    pub center_roi: bool,
+
+    /// [default: 1]
+    #[arg(long)]
+    pub binning_horizontal: Option<u32>,
+
+    /// [default: 1]
+    #[arg(long)]
+    pub binning_vertical: Option<u32>,
+
+    /// [default: sum]
+    #[arg(long, value_enum)]
+    pub binning_mode: Option<BinningMode>,
+
+    /// [default: 1]
+    #[arg(long)]
+    pub decimation_horizontal: Option<u32>,
+
+    /// [default: 1]
+    #[arg(long)]
+    pub decimation_vertical: Option<u32>,
+
+    /// Free-run at --fps or slave the camera to an external sync pulse
+    /// [default: free-run]
+    #[arg(long, value_enum)]
+    pub trigger: Option<TriggerMode>,
+
+    /// GPI line the external trigger is wired to [default: 1]
+    #[arg(long)]
+    pub trigger_line: Option<u32>,
+
+    /// Delay between the trigger edge and the start of exposure, in µs
+    /// [default: 0]
+    #[arg(long)]
+    pub trigger_delay: Option<u32>,
+
+    /// Strobe output as LINE:MODE[:inverted], MODE being exposure-active or
+    /// frame-active; may be repeated
+    #[arg(long)]
+    pub strobe: Vec<StrobeConfig>,
+
+    /// Refuse to record if the applied framerate or exposure differ from the
+    /// requested values by more than this fraction (e.g. 0.05); ROI and pixel
+    /// format must then match exactly
+    #[arg(long)]
+    pub settings_tolerance: Option<f32>,
+
+    /// Seconds recorded before the trigger [default: 0.5]
+    #[arg(long)]
+    pub t_before: Option<f32>,
+
+    /// Seconds recorded after the trigger [default: 1.0]
+    #[arg(long)]
+    pub t_after: Option<f32>,
 
-    #[arg(long, default_value_t = 500.0)]
-    pub fps: f32,
+    /// Longest manual recording started over the control socket, or clip
+    /// extended by overlapping triggers, in seconds [default: 10]
+    #[arg(long)]
+    pub t_manual_max: Option<f32>,
 
-    #[arg(long, default_value_t = 2000.0)]
-    pub exposure: f32,
+    /// Most frame data one clip holds in memory, in megabytes; longer
+    /// clips are saved early [default: 4096]
+    #[arg(long)]
+    pub max_clip_mb: Option<f32>,
 
-    #[arg(long, default_value_t = 2016)]
-    pub width: u32,
+    /// What to do with a trigger arriving while a clip is being recorded
+    /// [default: extend]
+    #[arg(long, value_enum)]
+    pub overlap: Option<OverlapPolicy>,
 
-    #[arg(long, default_value_t = 2016)]
-    pub height: u32,
+    /// Seconds after a recorded trigger during which others are ignored,
+    /// used with --overlap refractory [default: 1.0]
+    #[arg(long)]
+    pub refractory: Option<f32>,
 
-    #[arg(long, default_value_t = 1056)]
-    pub offset_x: u32,
+    /// Most clips being recorded or waiting to be written at once; triggers
+    /// beyond it are dropped [default: 8]
+    #[arg(long)]
+    pub max_pending_clips: Option<usize>,
 
-    #[arg(long, default_value_t = 170)]
-    pub offset_y: u32,
+    /// ZeroMQ transport used with --address and the ports [default: tcp]
+    #[arg(long, value_enum)]
+    pub transport: Option<Transport>,
 
-    #[arg(long, default_value_t = 0.5)]
-    pub t_before: f32,
+    /// Tracker host for tcp, socket path prefix for ipc, name for inproc
+    /// [default: 127.0.0.1]
+    #[arg(long)]
+    pub address: Option<String>,
 
-    #[arg(long, default_value_t = 1.0)]
-    pub t_after: f32,
+    /// Port the tracker publishes triggers on [default: 5556]
+    #[arg(long)]
+    pub sub_port: Option<String>,
 
-    #[arg(long, default_value_t = String::from("127.0.0.1"))]
-    pub address: String,
+    /// Port the tracker answers the handshake on [default: 5557]
+    #[arg(long)]
+    pub req_port: Option<String>,
 
-    #[arg(long, default_value_t = String::from("5556"))]
-    pub sub_port: String,
+    /// Full trigger endpoint, e.g. tcp://tracker:5556, instead of
+    /// --transport/--address/--sub-port
+    #[arg(long)]
+    pub sub_endpoint: Option<String>,
 
-    #[arg(long, default_value_t = String::from("5557"))]
-    pub req_port: String,
+    /// Full handshake endpoint instead of --transport/--address/--req-port
+    #[arg(long)]
+    pub req_endpoint: Option<String>,
+
+    /// Wait for the tracker to answer a hello on the REQ socket before
+    /// recording
+    #[arg(long, default_value_t = false)]
+    pub handshake: bool,
+
+    /// How long to wait for the handshake reply, in ms [default: 5000]
+    #[arg(long)]
+    pub handshake_timeout: Option<u64>,
+
+    /// Subscribed topic as TOPIC=ACTION, ACTION being trigger, control,
+    /// parameter-update or ignore; may be repeated [default: trigger=trigger]
+    #[arg(long)]
+    pub topic: Vec<TopicConfig>,
+
+    /// Record whenever Enter is pressed; a line typed before becomes the
+    /// clip label
+    #[arg(long, default_value_t = false)]
+    pub key_trigger: bool,
+
+    /// Record every SECS seconds
+    #[arg(long, value_name = "SECS")]
+    pub timer_trigger: Option<f32>,
+
+    /// Record when the process receives SIGUSR1
+    #[arg(long, default_value_t = false)]
+    pub signal_trigger: bool,
+
+    /// Ignore tracker triggers slower than this speed
+    #[arg(long)]
+    pub min_speed: Option<f64>,
+
+    /// Ignore tracker triggers faster than this speed
+    #[arg(long)]
+    pub max_speed: Option<f64>,
+
+    /// Ignore tracker triggers whose P00, P11 or P22 exceeds this
+    #[arg(long)]
+    pub max_covariance: Option<f64>,
+
+    /// Seconds after an accepted tracker trigger during which further
+    /// triggers for the same obj_id are ignored
+    #[arg(long, value_name = "SECS")]
+    pub cooldown: Option<f32>,
+
+    /// Ignore tracker triggers once this many were accepted in the last
+    /// minute
+    #[arg(long)]
+    pub max_triggers_per_minute: Option<usize>,
+
+    /// Serve the JSON control API on a REP socket bound to this endpoint,
+    /// e.g. tcp://*:5558 [default: off]
+    #[arg(long)]
+    pub control_endpoint: Option<String>,
+
+    /// Publish recording events and status as JSON on a PUB socket bound to
+    /// this endpoint, e.g. tcp://*:5559 [default: off]
+    #[arg(long)]
+    pub event_endpoint: Option<String>,
+
+    /// Seconds between status messages on --event-endpoint [default: 1.0]
+    #[arg(long)]
+    pub status_interval: Option<f32>,
 
     #[arg(long, default_value_t = false)]
     pub debug: bool,
 
-    #[arg(long, default_value_t = String::from("None"))]
-    pub save_folder: String,
+    /// Root directory for saved clips; required unless set in --config
+    #[arg(long)]
+    pub save_folder: Option<PathBuf>,
+
+    /// Clip path below --save-folder, e.g.
+    /// "{date}/{camera_serial}/obj{obj_id}_f{frame}_{wallclock}"
+    /// [default: obj_id_{obj_id}_frame_{frame}]
+    #[arg(long)]
+    pub template: Option<PathTemplate>,
+
+    /// How the frames of each clip are stored; every format but video is
+    /// lossless, except ufmf outside its regions [default: video]
+    #[arg(long, value_enum)]
+    pub clip_format: Option<ClipFormat>,
+
+    /// Encoders to try in order for 8-bit clips, comma-separated; the first
+    /// one the local ffmpeg can use is taken [default: nvenc,vaapi,x264]
+    #[arg(long, value_enum, value_delimiter = ',')]
+    pub encoder: Vec<EncoderProfile>,
+
+    /// Constant quality of the encoder, lower is better [default: 23]
+    #[arg(long)]
+    pub crf: Option<u8>,
+
+    /// Encoder preset, e.g. veryfast for x264/x265 or p4 for nvenc
+    #[arg(long)]
+    pub preset: Option<String>,
+
+    /// Framerate the videos play at, e.g. 30 to watch a 500 fps clip in slow
+    /// motion [default: the acquisition framerate]
+    #[arg(long)]
+    pub playback_fps: Option<f32>,
+
+    /// DRM render node for --encoder vaapi [default: /dev/dri/renderD128]
+    #[arg(long)]
+    pub vaapi_device: Option<PathBuf>,
+
+    /// Difference from the background, in gray levels, above which a pixel
+    /// is stored with --clip-format ufmf [default: 15]
+    #[arg(long)]
+    pub ufmf_threshold: Option<u8>,
+
+    /// Side of the square tiles uFMF regions are made of, in pixels
+    /// [default: 32]
+    #[arg(long)]
+    pub ufmf_region_size: Option<u16>,
+
+    /// Use a synthetic camera instead of a physical XIMEA device
+    #[arg(long, default_value_t = false)]
+    pub simulate: bool,
+
+    /// [default: moving-bar]
+    #[arg(long, value_enum)]
+    pub sim_pattern: Option<FramePattern>,
+
+    /// Amplitude of the noise added to simulated frames, in gray levels
+    /// [default: 0]
+    #[arg(long)]
+    pub sim_noise: Option<f32>,
+
+    /// Probability of the simulated camera dropping a frame [default: 0]
+    #[arg(long)]
+    pub sim_drop_rate: Option<f64>,
+}
+
+impl RecordArgs {
+    /// Load `--config` (or the defaults) and apply every flag given on the
+    /// command line on top of it.
+    pub fn resolve(&self) -> anyhow::Result<AppConfig> {
+        let mut config = match &self.config {
+            Some(path) => AppConfig::load(path)?,
+            None => AppConfig::default(),
+        };
+
+        // --serial picks cameras from the file by serial, or adds new ones
+        // based on the first configured camera
+        if !self.serial.is_empty() {
+            let base = config.cameras.first().cloned().unwrap_or_default();
+            config.cameras = self
+                .serial
+                .iter()
+                .map(|serial| {
+                    config
+                        .cameras
+                        .iter()
+                        .find(|camera| camera.serial.as_ref() == Some(serial))
+                        .cloned()
+                        .unwrap_or_else(|| base.clone().with_serial(serial))
+                })
+                .collect();
+        }
+        for camera in &mut config.cameras {
+            self.override_camera(camera);
+        }
+
+        set(&mut config.recording.t_before, &self.t_before);
+        set(&mut config.recording.t_after, &self.t_after);
+        set(&mut config.recording.t_manual_max, &self.t_manual_max);
+        set(&mut config.recording.max_clip_mb, &self.max_clip_mb);
+        set(&mut config.recording.overlap, &self.overlap);
+        set(&mut config.recording.refractory_s, &self.refractory);
+        set(
+            &mut config.recording.max_pending_clips,
+            &self.max_pending_clips,
+        );
+        set(&mut config.messaging.transport, &self.transport);
+        set(&mut config.messaging.address, &self.address);
+        set(&mut config.messaging.sub_port, &self.sub_port);
+        set(&mut config.messaging.req_port, &self.req_port);
+        if self.sub_endpoint.is_some() {
+            config.messaging.sub_endpoint = self.sub_endpoint.clone();
+        }
+        if self.req_endpoint.is_some() {
+            config.messaging.req_endpoint = self.req_endpoint.clone();
+        }
+        config.messaging.handshake |= self.handshake;
+        set(
+            &mut config.messaging.handshake_timeout_ms,
+            &self.handshake_timeout,
+        );
+        if !self.topic.is_empty() {
+            config.messaging.topics = self.topic.clone();
+        }
+        config.manual_triggers.keyboard |= self.key_trigger;
+        if self.timer_trigger.is_some() {
+            config.manual_triggers.interval_s = self.timer_trigger;
+        }
+        config.manual_triggers.signal |= self.signal_trigger;
+        if self.min_speed.is_some() {
+            config.filter.min_speed = self.min_speed;
+        }
+        if self.max_speed.is_some() {
+            config.filter.max_speed = self.max_speed;
+        }
+        if self.max_covariance.is_some() {
+            config.filter.max_covariance = self.max_covariance;
+        }
+        if self.cooldown.is_some() {
+            config.filter.cooldown_s = self.cooldown;
+        }
+        if self.max_triggers_per_minute.is_some() {
+            config.filter.max_triggers_per_minute = self.max_triggers_per_minute;
+        }
+        if self.control_endpoint.is_some() {
+            config.messaging.control_endpoint = self.control_endpoint.clone();
+        }
+        if self.event_endpoint.is_some() {
+            config.messaging.event_endpoint = self.event_endpoint.clone();
+        }
+        set(
+            &mut config.messaging.status_interval_s,
+            &self.status_interval,
+        );
+        if self.save_folder.is_some() {
+            config.output.save_folder = self.save_folder.clone();
+        }
+        set(&mut config.output.template, &self.template);
+        set(&mut config.output.format, &self.clip_format);
+        if !self.encoder.is_empty() {
+            config.output.encoder.chain = self.encoder.clone();
+        }
+        set(&mut config.output.encoder.crf, &self.crf);
+        if self.preset.is_some() {
+            config.output.encoder.preset = self.preset.clone();
+        }
+        set(&mut config.output.encoder.vaapi_device, &self.vaapi_device);
+        set(&mut config.output.ufmf.threshold, &self.ufmf_threshold);
+        set(&mut config.output.ufmf.region_size, &self.ufmf_region_size);
+        if self.playback_fps.is_some() {
+            config.output.playback_fps = self.playback_fps;
+        }
+
+        if self.simulate {
+            config
+                .simulation
+                .get_or_insert_with(SimulationConfig::default);
+        }
+        if let Some(sim) = &mut config.simulation {
+            set(&mut sim.pattern, &self.sim_pattern);
+            set(&mut sim.noise, &self.sim_noise);
+            set(&mut sim.drop_rate, &self.sim_drop_rate);
+        }
+
+        Ok(config)
+    }
+
+    fn override_camera(&self, camera: &mut CameraConfig) {
+        set(&mut camera.fps, &self.fps);
+        set(&mut camera.exposure, &self.exposure);
+        match self.exposure_mode {
+            Some(ExposureModeArg::Manual) => camera.exposure_mode = ExposureMode::Manual,
+            Some(ExposureModeArg::Auto) if camera.exposure_mode == ExposureMode::Manual => {
+                camera.exposure_mode = ExposureMode::Auto(Default::default())
+            }
+            _ => {}
+        }
+        if let ExposureMode::Auto(auto) = &mut camera.exposure_mode {
+            set(&mut auto.max_exposure_us, &self.ae_max_exposure);
+            set(&mut auto.max_gain_db, &self.ae_max_gain);
+            set(&mut auto.target_level, &self.ae_target_level);
+            set(&mut auto.priority, &self.ae_priority);
+        }
+        set(&mut camera.pixel_format, &self.pixel_format);
+        set(&mut camera.width, &self.width);
+        set(&mut camera.height, &self.height);
+        set(&mut camera.offset_x, &self.offset_x);
+        set(&mut camera.offset_y, &self.offset_y);
+        camera.center_roi |= self.center_roi;
+        set(&mut camera.binning.horizontal, &self.binning_horizontal);
+        set(&mut camera.binning.vertical, &self.binning_vertical);
+        set(&mut camera.binning.mode, &self.binning_mode);
+        set(
+            &mut camera.decimation.horizontal,
+            &self.decimation_horizontal,
+        );
+        set(&mut camera.decimation.vertical, &self.decimation_vertical);
+        set(&mut camera.trigger.mode, &self.trigger);
+        set(&mut camera.trigger.gpi_line, &self.trigger_line);
+        set(&mut camera.trigger.delay_us, &self.trigger_delay);
+        if !self.strobe.is_empty() {
+            camera.strobes = self.strobe.clone();
+        }
+        if self.settings_tolerance.is_some() {
+            camera.settings_tolerance = self.settings_tolerance;
+        }
+    }
+}
+
+/// Replace `target` if the flag was given.
+fn set<T: Clone>(target: &mut T, flag: &Option<T>) {
+    if let Some(value) = flag {
+        *target = value.clone();
+    }
 }
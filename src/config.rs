@@ -0,0 +1,564 @@
+// External crate imports
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+// Standard library imports
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+// Current crate imports
+use crate::encoders::EncoderConfig;
+use crate::filters::TriggerFilterConfig;
+use crate::fmf::UfmfConfig;
+use crate::simulated::SimulationConfig;
+use crate::templates::PathTemplate;
+use crate::ximea_camera::CameraConfig;
+
+/// File formats accepted by `--config` and produced by `print-config`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Pick the format from the file extension.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("toml") => Ok(Self::Toml),
+            Some("yaml" | "yml") => Ok(Self::Yaml),
+            Some("json") => Ok(Self::Json),
+            _ => bail!(
+                "Cannot tell the format of {}, use a .toml, .yaml or .json extension",
+                path.display()
+            ),
+        }
+    }
+
+    fn parse(self, text: &str) -> Result<Value> {
+        Ok(match self {
+            Self::Toml => toml::from_str(text)?,
+            Self::Yaml => serde_yaml::from_str(text)?,
+            Self::Json => serde_json::from_str(text)?,
+        })
+    }
+
+    pub fn render<T: Serialize>(self, value: &T) -> Result<String> {
+        Ok(match self {
+            Self::Toml => toml::to_string_pretty(value)?,
+            Self::Yaml => serde_yaml::to_string(value)?,
+            Self::Json => serde_json::to_string_pretty(value)?,
+        })
+    }
+}
+
+/// How much is recorded around each trigger.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RecordingConfig {
+    /// Seconds kept before the trigger
+    pub t_before: f32,
+    /// Seconds recorded after the trigger
+    pub t_after: f32,
+    /// Longest manual or extended recording in seconds after the pre-trigger
+    /// frames, after which it is saved
+    pub t_manual_max: f32,
+    /// Most frame data a single clip holds in memory, in megabytes. Clips
+    /// reaching it are saved like those reaching `t_manual_max`, which
+    /// bounds memory at high framerates and resolutions
+    pub max_clip_mb: f32,
+    /// What to do with a trigger arriving while a clip is being recorded
+    pub overlap: OverlapPolicy,
+    /// Minimum seconds between clips with the `refractory` policy
+    pub refractory_s: f32,
+    /// Most clips being recorded or waiting for the writer at once; later
+    /// triggers are dropped
+    pub max_pending_clips: usize,
+}
+
+impl Default for RecordingConfig {
+    fn default() -> Self {
+        Self {
+            t_before: 0.5,
+            t_after: 1.0,
+            t_manual_max: 10.0,
+            max_clip_mb: 4096.0,
+            overlap: OverlapPolicy::default(),
+            refractory_s: 1.0,
+            max_pending_clips: 8,
+        }
+    }
+}
+
+impl RecordingConfig {
+    /// Longest manual or extended recording in frames at `fps`.
+    pub fn max_frames(&self, fps: f32) -> usize {
+        (self.t_manual_max * fps) as usize
+    }
+
+    /// `max_clip_mb` in bytes.
+    pub fn max_clip_bytes(&self) -> usize {
+        (self.max_clip_mb * 1e6) as usize
+    }
+
+    /// Number of frames before and after the trigger at `fps`.
+    pub fn frames(&self, fps: f32) -> (usize, usize) {
+        (
+            (self.t_before * fps) as usize,
+            (self.t_after * fps) as usize,
+        )
+    }
+}
+
+/// What to do with a trigger arriving while the clip of an earlier one is
+/// still being recorded.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum OverlapPolicy {
+    /// Keep recording the current clip for `t_after` from the new trigger
+    #[default]
+    Extend,
+    /// Record a clip per trigger, each with its own pre- and post-trigger
+    /// frames
+    Separate,
+    /// Ignore triggers within `refractory_s` of the last recorded one
+    Refractory,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum Transport {
+    /// `tcp://<address>:<port>`
+    #[default]
+    Tcp,
+    /// `ipc://<address>-<port>`, `address` being a socket path prefix
+    Ipc,
+    /// `inproc://<address>-<port>`, only reachable from this process
+    Inproc,
+}
+
+/// What to do with messages published on a topic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TopicAction {
+    /// JSON `KalmanEstimateRow` that starts a recording; "kill" shuts down
+    Trigger,
+    /// Plain-text command such as "kill"
+    Control,
+    /// `name value` or `{"name": ..., "value": ...}` applied to the cameras
+    ParameterUpdate,
+    /// Dropped without a warning
+    Ignore,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct TopicConfig {
+    pub topic: String,
+    pub action: TopicAction,
+}
+
+impl fmt::Display for TopicConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let action = TopicAction::to_possible_value(&self.action).expect("no skipped variants");
+        write!(f, "{}={}", self.topic, action.get_name())
+    }
+}
+
+/// Parses `TOPIC=ACTION`, e.g. `set=parameter-update`.
+impl FromStr for TopicConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (topic, action) = s
+            .split_once('=')
+            .ok_or_else(|| format!("Expected TOPIC=ACTION, got {:?}", s))?;
+        Ok(Self {
+            topic: topic.trim().to_string(),
+            action: TopicAction::from_str(action.trim(), true)?,
+        })
+    }
+}
+
+fn default_topics() -> Vec<TopicConfig> {
+    vec![TopicConfig {
+        topic: "trigger".to_string(),
+        action: TopicAction::Trigger,
+    }]
+}
+
+/// Where the tracker publishes triggers and answers the startup handshake.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MessagingConfig {
+    pub transport: Transport,
+    pub address: String,
+    pub sub_port: String,
+    pub req_port: String,
+    /// Full endpoint such as `tcp://tracker:5556`, overriding transport,
+    /// address and port
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sub_endpoint: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub req_endpoint: Option<String>,
+    /// Say hello to the tracker on the REQ socket before recording
+    pub handshake: bool,
+    /// How long to wait for the handshake reply, in ms
+    pub handshake_timeout_ms: u64,
+    /// Subscribed topics and what each of them does
+    pub topics: Vec<TopicConfig>,
+    /// Serve the JSON control API on a REP socket bound here, e.g.
+    /// `tcp://*:5558`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub control_endpoint: Option<String>,
+    /// Publish recording events and status on a PUB socket bound here,
+    /// e.g. `tcp://*:5559`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub event_endpoint: Option<String>,
+    /// Seconds between status messages on the event socket
+    pub status_interval_s: f32,
+}
+
+impl Default for MessagingConfig {
+    fn default() -> Self {
+        Self {
+            transport: Transport::Tcp,
+            address: "127.0.0.1".to_string(),
+            sub_port: "5556".to_string(),
+            req_port: "5557".to_string(),
+            sub_endpoint: None,
+            req_endpoint: None,
+            handshake: false,
+            handshake_timeout_ms: 5000,
+            topics: default_topics(),
+            control_endpoint: None,
+            event_endpoint: None,
+            status_interval_s: 1.0,
+        }
+    }
+}
+
+impl MessagingConfig {
+    pub fn sub_endpoint(&self) -> String {
+        self.sub_endpoint
+            .clone()
+            .unwrap_or_else(|| self.endpoint(&self.sub_port))
+    }
+
+    pub fn req_endpoint(&self) -> String {
+        self.req_endpoint
+            .clone()
+            .unwrap_or_else(|| self.endpoint(&self.req_port))
+    }
+
+    fn endpoint(&self, port: &str) -> String {
+        match self.transport {
+            Transport::Tcp => format!("tcp://{}:{}", self.address, port),
+            Transport::Ipc => format!("ipc://{}-{}", self.address, port),
+            Transport::Inproc => format!("inproc://{}-{}", self.address, port),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.address.trim().is_empty() {
+            return Err("Messaging address must not be empty".to_string());
+        }
+        if self.transport == Transport::Tcp {
+            for (name, port, endpoint) in [
+                ("sub", &self.sub_port, &self.sub_endpoint),
+                ("req", &self.req_port, &self.req_endpoint),
+            ] {
+                if endpoint.is_none() && port.parse::<u16>().is_err() {
+                    return Err(format!("Invalid {} port {:?}", name, port));
+                }
+            }
+        }
+        for endpoint in [
+            &self.sub_endpoint,
+            &self.req_endpoint,
+            &self.control_endpoint,
+            &self.event_endpoint,
+        ]
+        .into_iter()
+        .flatten()
+        {
+            if !["tcp://", "ipc://", "inproc://"]
+                .iter()
+                .any(|scheme| endpoint.starts_with(scheme))
+            {
+                return Err(format!(
+                    "Endpoint {:?} must start with tcp://, ipc:// or inproc://",
+                    endpoint
+                ));
+            }
+        }
+        if self.status_interval_s <= 0.0
+            || Duration::try_from_secs_f32(self.status_interval_s).is_err()
+        {
+            return Err("Status interval must be a positive number of seconds".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Trigger sources besides the tracker, for calibration and testing.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ManualTriggerConfig {
+    /// Record when Enter is pressed on stdin; a typed line becomes the label
+    pub keyboard: bool,
+    /// Record every this many seconds
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub interval_s: Option<f32>,
+    /// Record on SIGUSR1
+    pub signal: bool,
+}
+
+/// How the frames of a clip are stored.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipFormat {
+    /// 8-bit clips compressed with the `encoder` chain, deeper formats as
+    /// FFV1
+    #[default]
+    Video,
+    /// Every clip lossless as FFV1 in Matroska (`.mkv`)
+    Ffv1,
+    /// The frames as received in `.bin`, with an `.index.csv` of offsets
+    Raw,
+    /// A directory of one TIFF per frame
+    Tiff,
+    /// A directory of one PNG per frame
+    Png,
+    /// FlyMovieFormat v3 (`.fmf`), every frame uncompressed
+    Fmf,
+    /// Micro FlyMovieFormat (`.ufmf`): a background and the regions of each
+    /// frame that differ from it, 8-bit formats only
+    Ufmf,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct OutputConfig {
+    /// Root directory for all clips; required for recording
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub save_folder: Option<PathBuf>,
+    /// Clip path below `save_folder`, see `PathTemplate`
+    pub template: PathTemplate,
+    /// How the frames are stored
+    pub format: ClipFormat,
+    /// How 8-bit clips are compressed with `ClipFormat::Video`
+    pub encoder: EncoderConfig,
+    /// How foreground regions are found with `ClipFormat::Ufmf`
+    pub ufmf: UfmfConfig,
+    /// Framerate written to the videos instead of the acquisition rate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playback_fps: Option<f32>,
+}
+
+/// Complete configuration of a `record` run, as read from `--config` and
+/// overridden by command-line flags.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AppConfig {
+    pub cameras: Vec<CameraConfig>,
+    pub recording: RecordingConfig,
+    pub messaging: MessagingConfig,
+    pub output: OutputConfig,
+    pub manual_triggers: ManualTriggerConfig,
+    /// Rules tracker triggers have to pass to start a recording
+    pub filter: TriggerFilterConfig,
+    /// Record from a synthetic camera instead of XIMEA devices
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub simulation: Option<SimulationConfig>,
+}
+
+impl Default for AppConfig {
+    fn default() -> Self {
+        Self {
+            cameras: vec![CameraConfig::default()],
+            recording: RecordingConfig::default(),
+            messaging: MessagingConfig::default(),
+            output: OutputConfig::default(),
+            manual_triggers: ManualTriggerConfig::default(),
+            filter: TriggerFilterConfig::default(),
+            simulation: None,
+        }
+    }
+}
+
+impl AppConfig {
+    /// Read a TOML, YAML or JSON configuration file.
+    ///
+    /// Settings in the `camera` section are shared by every entry of the
+    /// `cameras` list, which only needs to hold what differs per camera
+    /// (usually just the serial). Without a `cameras` list the `camera`
+    /// section describes the only camera.
+    pub fn load(path: &Path) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file {}", path.display()))?;
+        let mut value = ConfigFormat::from_path(path)?
+            .parse(&text)
+            .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+
+        let Value::Object(root) = &mut value else {
+            bail!("Config file {} must contain a table", path.display());
+        };
+        let base = root
+            .remove("camera")
+            .unwrap_or_else(|| Value::Object(Map::new()));
+        let cameras = match root.remove("cameras") {
+            Some(Value::Array(cameras)) if !cameras.is_empty() => cameras
+                .into_iter()
+                .map(|camera| {
+                    let mut merged = base.clone();
+                    merge(&mut merged, camera);
+                    merged
+                })
+                .collect(),
+            Some(Value::Array(_)) | None => vec![base],
+            Some(_) => bail!("`cameras` in {} must be a list", path.display()),
+        };
+        root.insert("cameras".to_string(), Value::Array(cameras));
+
+        serde_json::from_value(value)
+            .with_context(|| format!("Invalid configuration in {}", path.display()))
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.cameras.is_empty() {
+            return Err("At least one camera must be configured".to_string());
+        }
+        for (i, camera) in self.cameras.iter().enumerate() {
+            camera
+                .validate()
+                .map_err(|e| format!("Camera {}: {}", i, e))?;
+            if self.cameras.len() > 1 && self.simulation.is_none() && camera.serial.is_none() {
+                return Err(format!(
+                    "Camera {} needs a serial number when recording from several cameras",
+                    i
+                ));
+            }
+            if camera.serial.is_some()
+                && self.cameras[..i]
+                    .iter()
+                    .any(|other| other.serial == camera.serial)
+            {
+                return Err(format!(
+                    "Camera {} is configured more than once",
+                    camera.serial.as_deref().unwrap_or_default()
+                ));
+            }
+        }
+        self.messaging.validate()?;
+        if self.output.save_folder.is_none() {
+            return Err(
+                "No save folder configured, pass --save-folder or set output.save_folder"
+                    .to_string(),
+            );
+        }
+        if [self.recording.t_before, self.recording.t_after]
+            .iter()
+            .any(|t| !t.is_finite() || *t < 0.0)
+        {
+            return Err("Recording times must be finite and not negative".to_string());
+        }
+        if !self.recording.t_manual_max.is_finite() || self.recording.t_manual_max <= 0.0 {
+            return Err("Maximum manual recording time must be positive".to_string());
+        }
+        if !self.recording.max_clip_mb.is_finite() || self.recording.max_clip_mb <= 0.0 {
+            return Err("Maximum clip size must be positive".to_string());
+        }
+        if self.recording.max_pending_clips == 0 {
+            return Err("At least one pending clip must be allowed".to_string());
+        }
+        if Duration::try_from_secs_f32(self.recording.refractory_s).is_err() {
+            return Err("Refractory period must be a non-negative number of seconds".to_string());
+        }
+        if self
+            .manual_triggers
+            .interval_s
+            .is_some_and(|s| s <= 0.0 || Duration::try_from_secs_f32(s).is_err())
+        {
+            return Err("Timer trigger interval must be a positive number of seconds".to_string());
+        }
+        self.filter.validate()?;
+        self.output.encoder.validate()?;
+        self.output.ufmf.validate()?;
+        if self.output.format == ClipFormat::Ufmf {
+            if let Some(camera) = self
+                .cameras
+                .iter()
+                .find(|camera| camera.pixel_format.bytes_per_pixel() != 1)
+            {
+                return Err(format!(
+                    "uFMF stores 8-bit frames only, not {:?}",
+                    camera.pixel_format
+                ));
+            }
+        }
+        if self
+            .output
+            .playback_fps
+            .is_some_and(|fps| !fps.is_finite() || fps <= 0.0)
+        {
+            return Err("Playback framerate must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Recursively overlay `patch` onto `target`; tables are merged key by key,
+/// everything else is replaced.
+fn merge(target: &mut Value, patch: Value) {
+    match (target, patch) {
+        (Value::Object(target), Value::Object(patch)) => {
+            for (key, value) in patch {
+                merge(target.entry(key).or_insert(Value::Null), value);
+            }
+        }
+        (target, patch) => *target = patch,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> AppConfig {
+        let mut config = AppConfig::default();
+        config.output.save_folder = Some(PathBuf::from("clips"));
+        config
+    }
+
+    #[test]
+    fn default_config_with_save_folder_is_valid() {
+        assert_eq!(config().validate(), Ok(()));
+    }
+
+    #[test]
+    fn rejects_non_finite_recording_times() {
+        for value in [f32::NAN, f32::INFINITY] {
+            let mut invalid = config();
+            invalid.recording.t_before = value;
+            assert!(invalid.validate().is_err(), "t_before {}", value);
+
+            let mut invalid = config();
+            invalid.recording.t_after = value;
+            assert!(invalid.validate().is_err(), "t_after {}", value);
+
+            let mut invalid = config();
+            invalid.recording.t_manual_max = value;
+            assert!(invalid.validate().is_err(), "t_manual_max {}", value);
+
+            let mut invalid = config();
+            invalid.output.playback_fps = Some(value);
+            assert!(invalid.validate().is_err(), "playback_fps {}", value);
+        }
+    }
+}
@@ -0,0 +1,233 @@
+// External crate imports
+use anyhow::{Context, Result};
+use crossbeam::channel::{bounded, Sender};
+use serde::Deserialize;
+use serde_json::{json, Map, Value};
+
+// Standard library imports
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Current crate imports
+use crate::structs::{MessageType, TriggerTime};
+
+/// How long the cameras get to answer a request. Commands handled by the
+/// frame handler only run once the next frame arrives, so a camera waiting
+/// for an external trigger will time out.
+const REPLY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Pause before polling again after the poll failed.
+const POLL_RETRY: Duration = Duration::from_millis(100);
+
+/// Commands accepted on the control socket, as JSON objects tagged by
+/// `command`, e.g. `{"command": "set_param", "name": "exposure", "value": 1500}`.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(tag = "command", rename_all = "snake_case")]
+pub enum ControlCommand {
+    /// Frame counters, drops and recording state
+    Status,
+    /// Start a manual clip, including the frames kept before the trigger
+    StartRecording,
+    /// Finish the manual clip and save it
+    StopRecording,
+    /// Change `exposure` (µs), `gain` (dB) or `fps` while recording
+    SetParam {
+        name: String,
+        value: Value,
+    },
+    GetParam {
+        name: String,
+    },
+    /// Save the next frame as a PNG image
+    Snapshot,
+    /// Stop recording and exit, like "kill"
+    Shutdown,
+}
+
+#[derive(Debug, Deserialize)]
+struct Request {
+    #[serde(flatten)]
+    command: ControlCommand,
+    /// Serial of the camera to address; all cameras answer if missing
+    #[serde(default)]
+    camera: Option<String>,
+}
+
+/// A command on its way to the camera threads, which answer on `reply`.
+#[derive(Debug, Clone)]
+pub struct ControlRequest {
+    pub command: ControlCommand,
+    pub camera: Option<String>,
+    /// When the request arrived, the start of manual recordings
+    pub received: TriggerTime,
+    pub reply: Sender<ControlReply>,
+}
+
+#[derive(Debug)]
+pub struct ControlReply {
+    pub camera: String,
+    pub result: Result<Value, String>,
+}
+
+impl ControlRequest {
+    /// Whether the camera called `camera_serial` has to answer.
+    pub fn addresses(&self, camera_serial: &str) -> bool {
+        self.camera.as_deref().is_none_or(|c| c == camera_serial)
+    }
+
+    pub fn answer(&self, camera_serial: &str, result: Result<Value>) {
+        let reply = ControlReply {
+            camera: camera_serial.to_string(),
+            result: result.map_err(|e| format!("{:#}", e)),
+        };
+        if self.reply.send(reply).is_err() {
+            log::warn!(
+                "Control request {:?} timed out before {} answered",
+                self.command,
+                camera_serial
+            );
+        }
+    }
+}
+
+/// Bind the REP socket the control API is served on.
+pub fn bind_control_socket(context: &zmq::Context, endpoint: &str) -> Result<zmq::Socket> {
+    let socket = context
+        .socket(zmq::REP)
+        .context("Failed to create ZMQ socket")?;
+    log::info!("Serving control requests on {}", endpoint);
+    socket
+        .bind(endpoint)
+        .with_context(|| format!("Failed to bind control socket to {}", endpoint))?;
+    Ok(socket)
+}
+
+/// Answer JSON requests on the REP `socket` until `shutdown` receives
+/// anything. Polling errors are logged and retried, so the control socket
+/// stays up for as long as the recording runs.
+///
+/// Camera commands are passed to the camera threads through `messages`,
+/// the channel the subscriber feeds, and every addressed camera in
+/// `cameras` answers. Replies are `{"ok": true, "cameras": {<serial>: ...}}`
+/// or `{"ok": false, "error": ...}`.
+pub fn serve_control_requests(
+    socket: zmq::Socket,
+    shutdown: zmq::Socket,
+    cameras: Vec<String>,
+    messages: Sender<MessageType>,
+) {
+    loop {
+        let mut items = [
+            socket.as_poll_item(zmq::POLLIN),
+            shutdown.as_poll_item(zmq::POLLIN),
+        ];
+        match zmq::poll(&mut items, -1) {
+            Ok(_) => {}
+            // interrupted by a signal, e.g. SIGUSR1 for a manual trigger
+            Err(zmq::Error::EINTR) => continue,
+            Err(e) => {
+                log::error!("Failed to poll control socket: {:?}", e);
+                thread::sleep(POLL_RETRY);
+                continue;
+            }
+        }
+        if items[1].is_readable() {
+            log::info!("Shutdown requested, stopping control thread.");
+            break;
+        }
+        if !items[0].is_readable() {
+            continue;
+        }
+
+        let request = match socket.recv_bytes(0) {
+            Ok(request) => request,
+            Err(e) => {
+                log::error!("Failed to receive control request: {:?}", e);
+                continue;
+            }
+        };
+        let reply = handle_request(&request, &cameras, &messages);
+        log::debug!("Control reply: {}", reply);
+        // a REP socket must answer before it can receive again
+        if let Err(e) = socket.send(reply.to_string().as_str(), 0) {
+            log::error!("Failed to send control reply: {:?}", e);
+        }
+    }
+}
+
+fn handle_request(request: &[u8], cameras: &[String], messages: &Sender<MessageType>) -> Value {
+    let request: Request = match serde_json::from_slice(request) {
+        Ok(request) => request,
+        Err(e) => return error_reply(format!("Invalid request: {}", e)),
+    };
+    log::info!("Control request: {:?}", request);
+
+    let addressed: Vec<&String> = cameras
+        .iter()
+        .filter(|camera| request.camera.as_ref().is_none_or(|c| c == *camera))
+        .collect();
+    if addressed.is_empty() {
+        return error_reply(format!(
+            "Unknown camera {:?}, expected one of {}",
+            request.camera.unwrap_or_default(),
+            cameras.join(", ")
+        ));
+    }
+
+    if request.command == ControlCommand::Shutdown {
+        let kill = MessageType::Control {
+            topic: "control".to_string(),
+            command: "kill".to_string(),
+        };
+        return match messages.send(kill) {
+            Ok(()) => json!({ "ok": true }),
+            Err(_) => error_reply("Already shutting down".to_string()),
+        };
+    }
+
+    let (reply, replies) = bounded(addressed.len());
+    let forwarded = MessageType::Command(ControlRequest {
+        command: request.command,
+        camera: request.camera,
+        received: TriggerTime::now(),
+        reply,
+    });
+    if messages.send(forwarded).is_err() {
+        return error_reply("Already shutting down".to_string());
+    }
+
+    let deadline = Instant::now() + REPLY_TIMEOUT;
+    let mut results = Map::new();
+    let mut errors = Vec::new();
+    while results.len() < addressed.len() {
+        let Ok(reply) = replies.recv_deadline(deadline) else {
+            break;
+        };
+        match reply.result {
+            Ok(value) => {
+                results.insert(reply.camera, value);
+            }
+            Err(e) => {
+                errors.push(format!("{}: {}", reply.camera, e));
+                results.insert(reply.camera, json!({ "error": e }));
+            }
+        }
+    }
+    for camera in addressed {
+        if !results.contains_key(camera.as_str()) {
+            errors.push(format!("{}: no answer", camera));
+            results.insert(camera.clone(), json!({ "error": "no answer" }));
+        }
+    }
+
+    if errors.is_empty() {
+        json!({ "ok": true, "cameras": results })
+    } else {
+        json!({ "ok": false, "error": errors.join("; "), "cameras": results })
+    }
+}
+
+fn error_reply(error: String) -> Value {
+    log::warn!("Control request failed: {}", error);
+    json!({ "ok": false, "error": error })
+}
@@ -0,0 +1,526 @@
+// External crate imports
+use anyhow::{bail, Context, Result};
+use clap::ValueEnum;
+use ffmpeg::software::scaling;
+use ffmpeg::{codec, ffi, format, format::Pixel, frame, Dictionary, Packet, Rational};
+use ffmpeg_next as ffmpeg;
+use serde::{Deserialize, Serialize};
+
+// Standard library imports
+use std::ffi::CString;
+use std::fmt;
+use std::os::raw::c_int;
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::ptr;
+
+// Current crate imports
+use crate::structs::{ImageData, PixelFormat};
+
+/// Encoders clips can be compressed with. 8-bit clips only; deeper formats
+/// are always written losslessly.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum EncoderProfile {
+    /// H.264 on an NVIDIA GPU (`h264_nvenc`)
+    Nvenc,
+    /// H.264 through VA-API on Intel/AMD GPUs (`h264_vaapi`)
+    Vaapi,
+    /// H.264 in software (`libx264`)
+    X264,
+    /// H.265 in software (`libx265`)
+    X265,
+}
+
+impl EncoderProfile {
+    /// Name of the ffmpeg encoder.
+    pub fn codec(self) -> &'static str {
+        match self {
+            EncoderProfile::Nvenc => "h264_nvenc",
+            EncoderProfile::Vaapi => "h264_vaapi",
+            EncoderProfile::X264 => "libx264",
+            EncoderProfile::X265 => "libx265",
+        }
+    }
+
+    fn default_preset(self) -> Option<&'static str> {
+        match self {
+            EncoderProfile::Nvenc => Some("p4"),
+            EncoderProfile::Vaapi => None,
+            EncoderProfile::X264 | EncoderProfile::X265 => Some("veryfast"),
+        }
+    }
+}
+
+impl fmt::Display for EncoderProfile {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = self
+            .to_possible_value()
+            .map(|value| value.get_name().to_string())
+            .unwrap_or_default();
+        f.write_str(&name)
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct EncoderConfig {
+    /// Encoders to try in order; the first one the local ffmpeg can use is
+    /// taken for every clip
+    pub chain: Vec<EncoderProfile>,
+    /// Constant quality, lower is better: `-crf` for x264/x265, `-cq` for
+    /// nvenc and `-qp` for VA-API
+    pub crf: u8,
+    /// Encoder preset, e.g. `veryfast` for x264/x265 or `p4` for nvenc;
+    /// each encoder's own default if unset
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub preset: Option<String>,
+    /// DRM render node used by VA-API
+    pub vaapi_device: PathBuf,
+}
+
+impl Default for EncoderConfig {
+    fn default() -> Self {
+        Self {
+            chain: vec![
+                EncoderProfile::Nvenc,
+                EncoderProfile::Vaapi,
+                EncoderProfile::X264,
+            ],
+            crf: 23,
+            preset: None,
+            vaapi_device: PathBuf::from("/dev/dri/renderD128"),
+        }
+    }
+}
+
+impl EncoderConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        if self.chain.is_empty() {
+            return Err("At least one encoder must be configured".to_string());
+        }
+        if self.crf > 51 {
+            return Err("Encoder quality must be between 0 and 51".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// How the frames of one pixel format are encoded.
+#[derive(Debug, Clone)]
+pub struct EncodeSettings {
+    pub codec: &'static str,
+    /// Container extension
+    pub extension: &'static str,
+    /// Pixel format the camera's frames are read as
+    pub input: Pixel,
+    /// Pixel format the frames are converted to for the codec
+    pub pixel: Pixel,
+    /// Private options of the codec, e.g. `crf`
+    pub options: Vec<(&'static str, String)>,
+    /// VA-API device the frames are uploaded to, for codecs that encode on
+    /// the GPU
+    pub vaapi_device: Option<PathBuf>,
+}
+
+/// Initialize the ffmpeg libraries, before any clip is encoded.
+pub fn init() -> Result<()> {
+    ffmpeg::init().context("Failed to initialize ffmpeg")?;
+    // codec errors are reported on stderr, next to our own
+    ffmpeg::log::set_level(ffmpeg::log::Level::Error);
+    Ok(())
+}
+
+impl EncodeSettings {
+    /// Lossless FFV1 in Matroska, which keeps every bit of every pixel
+    /// format. Bayer frames are stored as the raw mosaic rather than
+    /// debayered, and color frames as planar RGB.
+    pub fn ffv1(pixel_format: PixelFormat) -> Self {
+        let (input, pixel) = match pixel_format {
+            PixelFormat::Rgb24 => (Pixel::BGR24, Pixel::GBRP),
+            format if format.is_bayer() => (Pixel::GRAY8, Pixel::GRAY8),
+            format => (ffmpeg_pixel(format), ffmpeg_pixel(format)),
+        };
+        Self {
+            codec: "ffv1",
+            extension: "mkv",
+            input,
+            pixel,
+            options: vec![("level", "3".to_string())],
+            vaapi_device: None,
+        }
+    }
+}
+
+/// The encoder picked from an `EncoderConfig`, with its settings.
+#[derive(Debug, Clone)]
+pub struct Encoder {
+    pub profile: EncoderProfile,
+    config: EncoderConfig,
+}
+
+impl Encoder {
+    /// Take the first encoder of the chain that ffmpeg was built with and
+    /// that opens, which rules out hardware encoders without the hardware.
+    pub fn select(config: &EncoderConfig) -> Result<Self> {
+        init()?;
+
+        for &profile in &config.chain {
+            let encoder = Self {
+                profile,
+                config: config.clone(),
+            };
+            match encoder.probe() {
+                Ok(()) => {
+                    log::info!("Encoding clips with {}", profile.codec());
+                    return Ok(encoder);
+                }
+                Err(e) => log::warn!("Cannot encode with {}: {:#}", profile.codec(), e),
+            }
+        }
+        bail!(
+            "None of the encoders {} works with the local ffmpeg",
+            config
+                .chain
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        )
+    }
+
+    /// How to encode frames of `pixel_format`.
+    ///
+    /// The profile cannot be relied on to carry more than 8 bits of gray,
+    /// so deeper formats go to lossless FFV1 in Matroska to keep their full
+    /// bit depth.
+    pub fn settings(&self, pixel_format: PixelFormat) -> EncodeSettings {
+        if pixel_format.bytes_per_pixel() == 2 {
+            return EncodeSettings::ffv1(pixel_format);
+        }
+
+        let profile = self.profile;
+        let pixel = match profile {
+            EncoderProfile::X264 | EncoderProfile::X265 if pixel_format == PixelFormat::Mono8 => {
+                Pixel::GRAY8
+            }
+            // uploaded to the GPU as NV12
+            EncoderProfile::Vaapi => Pixel::NV12,
+            // the GPU encoder takes no gray input, and color goes to YUV
+            _ => Pixel::YUV420P,
+        };
+        let mut options = Vec::new();
+        if let Some(preset) = self.config.preset.as_deref().or(profile.default_preset()) {
+            options.push(("preset", preset.to_string()));
+        }
+        let quality = self.config.crf.to_string();
+        match profile {
+            EncoderProfile::Nvenc => options.extend([
+                ("tune", "hq".to_string()),
+                ("rc", "vbr".to_string()),
+                ("cq", quality),
+            ]),
+            EncoderProfile::Vaapi => options.push(("qp", quality)),
+            EncoderProfile::X264 | EncoderProfile::X265 => options.push(("crf", quality)),
+        }
+
+        EncodeSettings {
+            codec: profile.codec(),
+            extension: "mp4",
+            input: ffmpeg_pixel(pixel_format),
+            pixel,
+            options,
+            vaapi_device: (profile == EncoderProfile::Vaapi)
+                .then(|| self.config.vaapi_device.clone()),
+        }
+    }
+
+    fn probe(&self) -> Result<()> {
+        let settings = self.settings(PixelFormat::Rgb24);
+        open_codec(&settings, 256, 256, Rational(1, 25), false).map(drop)
+    }
+}
+
+fn ffmpeg_pixel(pixel_format: PixelFormat) -> Pixel {
+    match pixel_format {
+        PixelFormat::Mono8 => Pixel::GRAY8,
+        PixelFormat::Mono10 => Pixel::GRAY10LE,
+        PixelFormat::Mono12 => Pixel::GRAY12LE,
+        PixelFormat::Mono16 => Pixel::GRAY16LE,
+        PixelFormat::BayerRggb8 => Pixel::BAYER_RGGB8,
+        PixelFormat::BayerBggr8 => Pixel::BAYER_BGGR8,
+        PixelFormat::BayerGrbg8 => Pixel::BAYER_GRBG8,
+        PixelFormat::BayerGbrg8 => Pixel::BAYER_GBRG8,
+        PixelFormat::Rgb24 => Pixel::BGR24,
+    }
+}
+
+/// Turn the return code of an ffmpeg C function into a `Result`.
+fn check(code: c_int) -> Result<(), ffmpeg::Error> {
+    if code < 0 {
+        Err(ffmpeg::Error::from(code))
+    } else {
+        Ok(())
+    }
+}
+
+/// Pool of frames on a VA-API device, which the frames of a clip are
+/// uploaded to before they are encoded.
+struct HwFrames(*mut ffi::AVBufferRef);
+
+impl HwFrames {
+    /// Open the VA-API `device` for `width`x`height` frames uploaded from
+    /// `pixel`.
+    fn vaapi(device: &Path, pixel: Pixel, width: u32, height: u32) -> Result<Self> {
+        let path = CString::new(device.as_os_str().as_bytes())
+            .with_context(|| format!("Invalid VA-API device {}", device.display()))?;
+        unsafe {
+            let mut device_ref = ptr::null_mut();
+            check(ffi::av_hwdevice_ctx_create(
+                &mut device_ref,
+                ffi::AVHWDeviceType::AV_HWDEVICE_TYPE_VAAPI,
+                path.as_ptr(),
+                ptr::null_mut(),
+                0,
+            ))
+            .with_context(|| format!("Failed to open VA-API device {}", device.display()))?;
+            // the frames context keeps its own reference to the device
+            let frames_ref = ffi::av_hwframe_ctx_alloc(device_ref);
+            ffi::av_buffer_unref(&mut device_ref);
+            if frames_ref.is_null() {
+                bail!("Failed to allocate VA-API frames");
+            }
+            let frames = Self(frames_ref);
+
+            let context = &mut *((*frames_ref).data as *mut ffi::AVHWFramesContext);
+            context.format = ffi::AVPixelFormat::AV_PIX_FMT_VAAPI;
+            context.sw_format = pixel.into();
+            context.width = width as c_int;
+            context.height = height as c_int;
+            context.initial_pool_size = 20;
+            check(ffi::av_hwframe_ctx_init(frames.0)).context("Failed to set up VA-API frames")?;
+            Ok(frames)
+        }
+    }
+
+    /// Copy `frame` to a frame of the pool, with the same timestamp.
+    fn upload(&self, frame: &frame::Video) -> Result<frame::Video> {
+        let mut uploaded = frame::Video::empty();
+        unsafe {
+            check(ffi::av_hwframe_get_buffer(self.0, uploaded.as_mut_ptr(), 0))
+                .context("Failed to get a VA-API frame")?;
+            check(ffi::av_hwframe_transfer_data(
+                uploaded.as_mut_ptr(),
+                frame.as_ptr(),
+                0,
+            ))
+            .context("Failed to upload frame to VA-API")?;
+        }
+        uploaded.set_pts(frame.pts());
+        Ok(uploaded)
+    }
+}
+
+impl Drop for HwFrames {
+    fn drop(&mut self) {
+        unsafe { ffi::av_buffer_unref(&mut self.0) }
+    }
+}
+
+/// Open the codec of `settings` for `width`x`height` frames, with the
+/// frames pool to upload them to for VA-API.
+fn open_codec(
+    settings: &EncodeSettings,
+    width: u32,
+    height: u32,
+    time_base: Rational,
+    global_header: bool,
+) -> Result<(ffmpeg::encoder::Video, Option<HwFrames>)> {
+    let codec = ffmpeg::encoder::find_by_name(settings.codec)
+        .with_context(|| format!("ffmpeg has no {} encoder", settings.codec))?;
+    let hw_frames = match &settings.vaapi_device {
+        Some(device) => Some(HwFrames::vaapi(device, settings.pixel, width, height)?),
+        None => None,
+    };
+    let mut context = codec::context::Context::new_with_codec(codec)
+        .encoder()
+        .video()?;
+    context.set_width(width);
+    context.set_height(height);
+    match &hw_frames {
+        Some(frames) => {
+            context.set_format(Pixel::VAAPI);
+            // the codec context releases its reference when it is freed
+            unsafe { (*context.as_mut_ptr()).hw_frames_ctx = ffi::av_buffer_ref(frames.0) };
+        }
+        None => context.set_format(settings.pixel),
+    }
+    context.set_time_base(time_base);
+    context.set_frame_rate(Some(time_base.invert()));
+    if global_header {
+        context.set_flags(codec::Flags::GLOBAL_HEADER);
+    }
+    let mut options = Dictionary::new();
+    for (name, value) in &settings.options {
+        options.set(name, value);
+    }
+    let encoder = context
+        .open_with(options)
+        .with_context(|| format!("Failed to open the {} encoder", settings.codec))?;
+    Ok((encoder, hw_frames))
+}
+
+/// Encodes the frames of one clip in-process and muxes them into a file.
+pub struct VideoEncoder {
+    output: format::context::Output,
+    encoder: ffmpeg::encoder::Video,
+    stream_index: usize,
+    /// Timestamp unit of the frames, one frame interval
+    time_base: Rational,
+    /// Frame in the camera's pixel format
+    input: frame::Video,
+    /// Conversion to the codec's pixel format, if it differs
+    converter: Option<(scaling::Context, frame::Video)>,
+    /// GPU frames the converted frames are uploaded to, for VA-API
+    hw_frames: Option<HwFrames>,
+}
+
+impl VideoEncoder {
+    /// Create `path` for frames like `first`, with timestamps counting
+    /// frames at `fps`, and embed `tags` in the container.
+    pub fn create(
+        path: &Path,
+        settings: &EncodeSettings,
+        first: &ImageData,
+        fps: f64,
+        tags: &[(&str, String)],
+    ) -> Result<Self> {
+        let (width, height) = (first.width, first.height);
+        let mut output =
+            format::output(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        let global_header = output
+            .format()
+            .flags()
+            .contains(format::Flags::GLOBAL_HEADER);
+        let time_base = Rational::from(fps).invert();
+        let (encoder, hw_frames) = open_codec(settings, width, height, time_base, global_header)?;
+
+        let mut stream = output
+            .add_stream(settings.codec)
+            .context("Failed to add video stream")?;
+        stream.set_time_base(time_base);
+        stream.set_parameters(&encoder);
+        let stream_index = stream.index();
+
+        let mut metadata = Dictionary::new();
+        for (key, value) in tags {
+            metadata.set(key, value);
+        }
+        output.set_metadata(metadata);
+        // MP4 drops tags it has no atom for unless asked to keep them
+        let mut options = Dictionary::new();
+        options.set("movflags", "use_metadata_tags");
+        output
+            .write_header_with(options)
+            .with_context(|| format!("Failed to write header of {}", path.display()))?;
+
+        let input_pixel = settings.input;
+        let converter = if input_pixel == settings.pixel {
+            None
+        } else {
+            let scaler = scaling::Context::get(
+                input_pixel,
+                width,
+                height,
+                settings.pixel,
+                width,
+                height,
+                scaling::Flags::BILINEAR,
+            )
+            .with_context(|| format!("Cannot convert {:?} to {:?}", input_pixel, settings.pixel))?;
+            Some((scaler, frame::Video::new(settings.pixel, width, height)))
+        };
+
+        Ok(Self {
+            output,
+            encoder,
+            stream_index,
+            time_base,
+            input: frame::Video::new(input_pixel, width, height),
+            converter,
+            hw_frames,
+        })
+    }
+
+    /// Encode `image` to be shown `pts` frame intervals after the start.
+    pub fn write(&mut self, image: &ImageData, pts: i64) -> Result<()> {
+        let row = image.width as usize * image.pixel_format.bytes_per_pixel();
+        if image.data.len() != row * image.height as usize {
+            bail!(
+                "Frame {} has {} bytes, expected {}",
+                image.nframe,
+                image.data.len(),
+                row * image.height as usize
+            );
+        }
+        let stride = self.input.stride(0);
+        let plane = self.input.data_mut(0);
+        for (source, target) in image.data.chunks_exact(row).zip(plane.chunks_mut(stride)) {
+            target[..row].copy_from_slice(source);
+        }
+
+        let frame = match &mut self.converter {
+            Some((scaler, converted)) => {
+                scaler
+                    .run(&self.input, converted)
+                    .context("Failed to convert frame")?;
+                converted
+            }
+            None => &mut self.input,
+        };
+        frame.set_pts(Some(pts));
+        let uploaded = match &self.hw_frames {
+            Some(hw_frames) => Some(hw_frames.upload(frame)?),
+            None => None,
+        };
+        self.encoder
+            .send_frame(uploaded.as_ref().unwrap_or(frame))
+            .with_context(|| format!("Failed to encode frame {}", image.nframe))?;
+        self.write_packets()
+    }
+
+    /// Flush the codec and finish the file.
+    pub fn finish(mut self) -> Result<()> {
+        self.encoder.send_eof().context("Failed to flush encoder")?;
+        self.write_packets()?;
+        self.output
+            .write_trailer()
+            .context("Failed to write trailer")
+    }
+
+    fn write_packets(&mut self) -> Result<()> {
+        // the muxer may have changed the stream's time base in write_header
+        let stream_time_base = self
+            .output
+            .stream(self.stream_index)
+            .context("Video stream disappeared")?
+            .time_base();
+        let mut packet = Packet::empty();
+        loop {
+            match self.encoder.receive_packet(&mut packet) {
+                Ok(()) => {
+                    packet.set_stream(self.stream_index);
+                    packet.rescale_ts(self.time_base, stream_time_base);
+                    packet
+                        .write_interleaved(&mut self.output)
+                        .context("Failed to write packet")?;
+                }
+                Err(ffmpeg::Error::Eof) => return Ok(()),
+                Err(ffmpeg::Error::Other { errno }) if errno == ffmpeg::error::EAGAIN => {
+                    return Ok(())
+                }
+                Err(e) => return Err(e).context("Failed to encode video"),
+            }
+        }
+    }
+}
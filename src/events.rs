@@ -0,0 +1,152 @@
+// External crate imports
+use anyhow::{Context, Result};
+use crossbeam::channel::{unbounded, Receiver, Sender};
+use serde::Serialize;
+
+// Standard library imports
+use std::path::PathBuf;
+use std::time::Duration;
+
+// Current crate imports
+use crate::helpers::time;
+use crate::structs::FrameStamp;
+
+/// Counters of one camera, published periodically and returned by the
+/// `status` control command.
+#[derive(Debug, Clone, Serialize)]
+pub struct CameraStatus {
+    pub camera: String,
+    /// Frames per second received since the previous status
+    pub fps: f64,
+    pub frames: u64,
+    /// Frames missing from the `nframe` sequence
+    pub dropped: u64,
+    pub last_nframe: Option<u32>,
+    /// Frames held for the next clip
+    pub buffered: usize,
+    /// Frames waiting for the frame handler
+    pub queue: usize,
+    /// `idle`, `trigger` or `manual`
+    pub recording: &'static str,
+    /// Free space on the save folder's filesystem
+    pub disk_free_bytes: Option<u64>,
+}
+
+/// Messages for downstream tools, published as JSON with `event` set to the
+/// snake_case variant name, which is also the topic.
+#[derive(Debug, Clone, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event {
+    /// The writer started saving a clip. Clips wait for their frames after
+    /// the trigger and then for the writer, so the event's `time` is later
+    /// than the recorded moment, which `trigger_time` and `trigger_frame`
+    /// give.
+    RecordingStarted {
+        camera: String,
+        path: PathBuf,
+        frames: usize,
+        /// When the trigger arrived, in RFC 3339
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trigger_time: Option<String>,
+        /// First frame taken at or after the trigger arrived
+        #[serde(skip_serializing_if = "Option::is_none")]
+        trigger_frame: Option<FrameStamp>,
+    },
+    RecordingSaved {
+        camera: String,
+        path: PathBuf,
+        frames: usize,
+        /// Clip length at the camera's framerate, if known
+        duration_s: Option<f64>,
+    },
+    RecordingFailed {
+        camera: String,
+        path: PathBuf,
+        error: String,
+    },
+    Status(CameraStatus),
+}
+
+impl Event {
+    pub fn topic(&self) -> &'static str {
+        match self {
+            Event::RecordingStarted { .. } => "recording_started",
+            Event::RecordingSaved { .. } => "recording_saved",
+            Event::RecordingFailed { .. } => "recording_failed",
+            Event::Status(_) => "status",
+        }
+    }
+}
+
+/// Handle for publishing events from any thread. The default one drops
+/// everything, for runs without a PUB endpoint.
+#[derive(Debug, Clone, Default)]
+pub struct EventPublisher {
+    sender: Option<Sender<(f64, Event)>>,
+    status_interval: Duration,
+}
+
+impl EventPublisher {
+    /// Create a publisher and the receiver to pass to `publish_events`.
+    /// Cameras report their status every `status_interval`.
+    pub fn new(status_interval: Duration) -> (Self, Receiver<(f64, Event)>) {
+        let (sender, receiver) = unbounded();
+        (
+            Self {
+                sender: Some(sender),
+                status_interval,
+            },
+            receiver,
+        )
+    }
+
+    pub fn status_interval(&self) -> Duration {
+        self.status_interval
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    /// Queue `event`, stamped with the current time.
+    pub fn publish(&self, event: Event) {
+        if let Some(sender) = &self.sender {
+            if sender.send((time(), event)).is_err() {
+                log::warn!("Event publisher has stopped");
+            }
+        }
+    }
+}
+
+/// Bind the PUB socket events are published on.
+pub fn bind_event_socket(context: &zmq::Context, endpoint: &str) -> Result<zmq::Socket> {
+    let socket = context
+        .socket(zmq::PUB)
+        .context("Failed to create ZMQ socket")?;
+    log::info!("Publishing events on {}", endpoint);
+    socket
+        .bind(endpoint)
+        .with_context(|| format!("Failed to bind event socket to {}", endpoint))?;
+    Ok(socket)
+}
+
+/// Send every event as a two-part `[topic, json]` message until all
+/// `EventPublisher`s are dropped. The JSON carries the Unix `time` the event
+/// was queued at.
+pub fn publish_events(socket: zmq::Socket, events: Receiver<(f64, Event)>) {
+    while let Ok((time, event)) = events.recv() {
+        let mut message = match serde_json::to_value(&event) {
+            Ok(message) => message,
+            Err(e) => {
+                log::error!("Failed to serialize event {:?}: {}", event, e);
+                continue;
+            }
+        };
+        message["time"] = time.into();
+        log::trace!("Publishing {}", message);
+        if let Err(e) = socket.send_multipart([event.topic(), &message.to_string()], 0) {
+            log::error!("Failed to publish event: {:?}", e);
+        }
+    }
+    log::info!("Event publisher stopped");
+}
@@ -0,0 +1,334 @@
+// External crate imports
+use serde::{Deserialize, Serialize};
+
+// Standard library imports
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+// Current crate imports
+use crate::structs::KalmanEstimateRow;
+
+/// Rules a `KalmanEstimateRow` has to pass before it starts a recording.
+/// Every rule is off unless set.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TriggerFilterConfig {
+    /// `[min, max]` of the position along each axis
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub x: Option<[f64; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub y: Option<[f64; 2]>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub z: Option<[f64; 2]>,
+    /// Bounds of the speed computed from `xvel`, `yvel` and `zvel`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min_speed: Option<f64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_speed: Option<f64>,
+    /// Largest position variance (`P00`, `P11`, `P22`) of a confident estimate
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_covariance: Option<f64>,
+    /// Seconds after an accepted trigger during which further triggers of
+    /// the same `obj_id` are rejected
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub cooldown_s: Option<f32>,
+    /// Triggers accepted per minute, over all objects
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max_triggers_per_minute: Option<usize>,
+}
+
+impl TriggerFilterConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        for (axis, range) in [("x", self.x), ("y", self.y), ("z", self.z)] {
+            if let Some([min, max]) = range {
+                if min > max {
+                    return Err(format!("Filter range of {} must be [min, max]", axis));
+                }
+            }
+        }
+        if let (Some(min), Some(max)) = (self.min_speed, self.max_speed) {
+            if min > max {
+                return Err("Filter min_speed must not exceed max_speed".to_string());
+            }
+        }
+        if self.max_covariance.is_some_and(|max| max <= 0.0) {
+            return Err("Filter max_covariance must be positive".to_string());
+        }
+        if self
+            .cooldown_s
+            .is_some_and(|s| Duration::try_from_secs_f32(s).is_err())
+        {
+            return Err("Filter cooldown must be a non-negative number of seconds".to_string());
+        }
+        if self.max_triggers_per_minute == Some(0) {
+            return Err("Filter max_triggers_per_minute must be positive".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// Applies a `TriggerFilterConfig` to the tracker's estimates, keeping the
+/// state the rate limits need.
+///
+/// The filter runs before the overlap policy, so the rate limits count the
+/// triggers it passes on, not the clips recorded: a trigger that only
+/// extends a clip, or is ignored within the refractory period, counts too.
+#[derive(Debug, Default)]
+pub struct TriggerFilter {
+    config: TriggerFilterConfig,
+    /// When each object's last trigger was accepted
+    last_trigger: HashMap<u32, Instant>,
+    /// Triggers accepted within the last minute
+    recent: VecDeque<Instant>,
+}
+
+impl TriggerFilter {
+    const WINDOW: Duration = Duration::from_secs(60);
+
+    pub fn new(config: TriggerFilterConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Whether `row` may trigger a recording now, or why not. Accepted rows
+    /// restart the cooldown of their object and count towards the triggers
+    /// per minute.
+    pub fn check(&mut self, row: &KalmanEstimateRow) -> Result<(), String> {
+        self.check_at(row, Instant::now())
+    }
+
+    fn check_at(&mut self, row: &KalmanEstimateRow, now: Instant) -> Result<(), String> {
+        self.check_estimate(row)?;
+
+        if let (Some(cooldown_s), Some(last)) =
+            (self.config.cooldown_s, self.last_trigger.get(&row.obj_id))
+        {
+            let since = now.duration_since(*last);
+            if since < Duration::from_secs_f32(cooldown_s) {
+                return Err(format!(
+                    "triggered {:.2} s ago, cooldown is {} s",
+                    since.as_secs_f32(),
+                    cooldown_s
+                ));
+            }
+        }
+        while self
+            .recent
+            .front()
+            .is_some_and(|time| now.duration_since(*time) >= Self::WINDOW)
+        {
+            self.recent.pop_front();
+        }
+        if let Some(max) = self.config.max_triggers_per_minute {
+            if self.recent.len() >= max {
+                return Err(format!("{} triggers accepted in the last minute", max));
+            }
+        }
+
+        if self.config.cooldown_s.is_some() {
+            self.last_trigger.insert(row.obj_id, now);
+        }
+        self.recent.push_back(now);
+        Ok(())
+    }
+
+    /// The rules on the estimate itself, which need no state.
+    fn check_estimate(&self, row: &KalmanEstimateRow) -> Result<(), String> {
+        let config = &self.config;
+        for (axis, value, range) in [
+            ("x", row.x, config.x),
+            ("y", row.y, config.y),
+            ("z", row.z, config.z),
+        ] {
+            if let Some([min, max]) = range {
+                if !(min..=max).contains(&value) {
+                    return Err(format!(
+                        "{} = {:.4} outside [{}, {}]",
+                        axis, value, min, max
+                    ));
+                }
+            }
+        }
+
+        let speed = (row.xvel.powi(2) + row.yvel.powi(2) + row.zvel.powi(2)).sqrt();
+        if let Some(min) = config.min_speed.filter(|min| speed < *min) {
+            return Err(format!("speed {:.4} below {}", speed, min));
+        }
+        if let Some(max) = config.max_speed.filter(|max| speed > *max) {
+            return Err(format!("speed {:.4} above {}", speed, max));
+        }
+
+        if let Some(max) = config.max_covariance {
+            for (name, value) in [("P00", row.P00), ("P11", row.P11), ("P22", row.P22)] {
+                // NaN from a diverged filter is not confident either
+                if value.is_nan() || value > max {
+                    return Err(format!("{} = {:.4} above {}", name, value, max));
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn row(obj_id: u32) -> KalmanEstimateRow {
+        KalmanEstimateRow {
+            obj_id,
+            ..KalmanEstimateRow::default()
+        }
+    }
+
+    fn filter(config: TriggerFilterConfig) -> TriggerFilter {
+        config.validate().unwrap();
+        TriggerFilter::new(config)
+    }
+
+    #[test]
+    fn ranges_include_their_bounds() {
+        let filter = filter(TriggerFilterConfig {
+            x: Some([-0.1, 0.1]),
+            z: Some([0.0, 0.3]),
+            ..TriggerFilterConfig::default()
+        });
+        let at = |x, z| KalmanEstimateRow { x, z, ..row(1) };
+
+        assert!(filter.check_estimate(&at(0.0, 0.1)).is_ok());
+        assert!(filter.check_estimate(&at(-0.1, 0.3)).is_ok());
+        assert!(filter.check_estimate(&at(0.11, 0.1)).is_err());
+        assert!(filter.check_estimate(&at(0.0, -0.01)).is_err());
+        assert!(filter.check_estimate(&at(f64::NAN, 0.1)).is_err());
+    }
+
+    #[test]
+    fn speed_is_bounded_on_both_sides() {
+        let filter = filter(TriggerFilterConfig {
+            min_speed: Some(0.1),
+            max_speed: Some(1.0),
+            ..TriggerFilterConfig::default()
+        });
+        let moving = |xvel, yvel| KalmanEstimateRow {
+            xvel,
+            yvel,
+            ..row(1)
+        };
+
+        assert!(filter.check_estimate(&moving(0.3, 0.4)).is_ok());
+        assert!(filter.check_estimate(&moving(0.05, 0.0)).is_err());
+        assert!(filter.check_estimate(&moving(0.8, 0.8)).is_err());
+    }
+
+    #[test]
+    fn nan_covariance_is_not_confident() {
+        let filter = filter(TriggerFilterConfig {
+            max_covariance: Some(0.01),
+            ..TriggerFilterConfig::default()
+        });
+
+        let confident = KalmanEstimateRow {
+            P00: 0.001,
+            P11: 0.001,
+            P22: 0.01,
+            ..row(1)
+        };
+        assert!(filter.check_estimate(&confident).is_ok());
+        let diverged = KalmanEstimateRow {
+            P11: f64::NAN,
+            ..confident
+        };
+        assert!(filter.check_estimate(&diverged).is_err());
+        let uncertain = KalmanEstimateRow {
+            P22: 0.02,
+            ..confident
+        };
+        assert!(filter.check_estimate(&uncertain).is_err());
+    }
+
+    #[test]
+    fn cooldown_is_per_object_and_restarts_on_accepted_triggers() {
+        let mut filter = filter(TriggerFilterConfig {
+            cooldown_s: Some(2.0),
+            ..TriggerFilterConfig::default()
+        });
+        let start = Instant::now();
+        let at = |s: f32| start + Duration::from_secs_f32(s);
+
+        assert!(filter.check_at(&row(1), at(0.0)).is_ok());
+        assert!(filter.check_at(&row(1), at(1.0)).is_err());
+        assert!(filter.check_at(&row(2), at(1.0)).is_ok());
+        // the rejected trigger at 1 s did not restart the cooldown
+        assert!(filter.check_at(&row(1), at(2.0)).is_ok());
+        assert!(filter.check_at(&row(1), at(3.5)).is_err());
+        assert!(filter.check_at(&row(1), at(4.0)).is_ok());
+    }
+
+    #[test]
+    fn triggers_per_minute_use_a_sliding_window() {
+        let mut filter = filter(TriggerFilterConfig {
+            max_triggers_per_minute: Some(2),
+            ..TriggerFilterConfig::default()
+        });
+        let start = Instant::now();
+        let at = |s: u64| start + Duration::from_secs(s);
+
+        assert!(filter.check_at(&row(1), at(0)).is_ok());
+        assert!(filter.check_at(&row(2), at(30)).is_ok());
+        assert!(filter.check_at(&row(3), at(59)).is_err());
+        // the first trigger left the window, the second has not
+        assert!(filter.check_at(&row(3), at(60)).is_ok());
+        assert!(filter.check_at(&row(4), at(61)).is_err());
+        assert!(filter.check_at(&row(4), at(90)).is_ok());
+    }
+
+    #[test]
+    fn rejected_estimates_do_not_count_towards_the_limits() {
+        let mut filter = filter(TriggerFilterConfig {
+            x: Some([0.0, 1.0]),
+            max_triggers_per_minute: Some(1),
+            ..TriggerFilterConfig::default()
+        });
+        let start = Instant::now();
+        let outside = KalmanEstimateRow { x: 2.0, ..row(1) };
+
+        assert!(filter.check_at(&outside, start).is_err());
+        assert!(filter.check_at(&row(1), start).is_ok());
+    }
+
+    #[test]
+    fn invalid_configs_are_rejected() {
+        let invalid = [
+            TriggerFilterConfig {
+                y: Some([1.0, 0.0]),
+                ..TriggerFilterConfig::default()
+            },
+            TriggerFilterConfig {
+                min_speed: Some(2.0),
+                max_speed: Some(1.0),
+                ..TriggerFilterConfig::default()
+            },
+            TriggerFilterConfig {
+                cooldown_s: Some(f32::NAN),
+                ..TriggerFilterConfig::default()
+            },
+            TriggerFilterConfig {
+                cooldown_s: Some(f32::INFINITY),
+                ..TriggerFilterConfig::default()
+            },
+            TriggerFilterConfig {
+                cooldown_s: Some(1e30),
+                ..TriggerFilterConfig::default()
+            },
+            TriggerFilterConfig {
+                max_triggers_per_minute: Some(0),
+                ..TriggerFilterConfig::default()
+            },
+        ];
+        for config in invalid {
+            assert!(config.validate().is_err(), "{:?}", config);
+        }
+    }
+}
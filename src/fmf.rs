@@ -0,0 +1,1046 @@
+// External crate imports
+use anyhow::{bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+// Standard library imports
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
+use std::iter;
+use std::path::Path;
+use std::sync::Arc;
+
+// Current crate imports
+use crate::structs::{ImageData, PixelFormat};
+
+const FMF_VERSION: u32 = 3;
+const UFMF_VERSION: u32 = 3;
+const KEYFRAME_CHUNK: u8 = 0;
+const FRAME_CHUNK: u8 = 1;
+const INDEX_CHUNK: u8 = 2;
+/// Keyframe type of the background, the name other uFMF readers look for
+const BACKGROUND: &str = "mean";
+/// Most frames the background is computed from, spread over the clip
+const BACKGROUND_FRAMES: usize = 50;
+
+/// FMF name of a pixel format and its bits per pixel. 10- and 12-bit
+/// frames are stored as `MONO16` with their raw values.
+fn fmf_format(pixel_format: PixelFormat) -> (&'static str, u32) {
+    match pixel_format {
+        PixelFormat::Mono8 => ("MONO8", 8),
+        PixelFormat::Mono10 | PixelFormat::Mono12 | PixelFormat::Mono16 => ("MONO16", 16),
+        PixelFormat::BayerRggb8 => ("RAW8:RGGB", 8),
+        PixelFormat::BayerBggr8 => ("RAW8:BGGR", 8),
+        PixelFormat::BayerGrbg8 => ("RAW8:GRBG", 8),
+        PixelFormat::BayerGbrg8 => ("RAW8:GBRG", 8),
+        PixelFormat::Rgb24 => ("RGB8", 24),
+    }
+}
+
+fn pixel_format_from_fmf(format: &str, bits_per_pixel: u32) -> Result<PixelFormat> {
+    Ok(match (format, bits_per_pixel) {
+        ("MONO8", 8) => PixelFormat::Mono8,
+        ("MONO16", 16) => PixelFormat::Mono16,
+        ("RAW8:RGGB", 8) => PixelFormat::BayerRggb8,
+        ("RAW8:BGGR", 8) => PixelFormat::BayerBggr8,
+        ("RAW8:GRBG", 8) => PixelFormat::BayerGrbg8,
+        ("RAW8:GBRG", 8) => PixelFormat::BayerGbrg8,
+        ("RGB8", 24) => PixelFormat::Rgb24,
+        _ => bail!(
+            "Unsupported format {} with {} bits per pixel",
+            format,
+            bits_per_pixel
+        ),
+    })
+}
+
+/// Swap blue and red, between the camera's and FMF's order of color frames.
+fn swap_red_blue(data: &[u8]) -> Vec<u8> {
+    data.chunks_exact(3)
+        .flat_map(|pixel| [pixel[2], pixel[1], pixel[0]])
+        .collect()
+}
+
+/// Camera timestamp of `image` in seconds.
+fn timestamp_s(image: &ImageData) -> f64 {
+    image.timestamp_raw as f64 / 1e9
+}
+
+fn read_array<const N: usize>(reader: &mut impl Read) -> io::Result<[u8; N]> {
+    let mut buf = [0; N];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+fn read_bytes(reader: &mut impl Read, len: usize) -> io::Result<Vec<u8>> {
+    let mut buf = vec![0; len];
+    reader.read_exact(&mut buf)?;
+    Ok(buf)
+}
+
+/// Check that `image` matches the movie it is added to.
+fn check_frame(
+    image: &ImageData,
+    pixel_format: PixelFormat,
+    width: u32,
+    height: u32,
+) -> Result<()> {
+    if (image.pixel_format, image.width, image.height) != (pixel_format, width, height) {
+        bail!(
+            "Frame {} is {}x{} {:?}, the movie {}x{} {:?}",
+            image.nframe,
+            image.width,
+            image.height,
+            image.pixel_format,
+            width,
+            height,
+            pixel_format
+        );
+    }
+    let expected = width as usize * height as usize * pixel_format.bytes_per_pixel();
+    if image.data.len() != expected {
+        bail!(
+            "Frame {} has {} bytes, expected {}",
+            image.nframe,
+            image.data.len(),
+            expected
+        );
+    }
+    Ok(())
+}
+
+/// Writes FlyMovieFormat v3: a header naming the pixel format, then every
+/// frame as an f64 timestamp followed by its pixels.
+pub struct FmfWriter<W: Write + Seek> {
+    writer: W,
+    pixel_format: PixelFormat,
+    width: u32,
+    height: u32,
+    n_frames: u64,
+    /// Where the frame count is stored in the header
+    n_frames_pos: u64,
+}
+
+impl FmfWriter<BufWriter<File>> {
+    pub fn create(path: &Path, pixel_format: PixelFormat, width: u32, height: u32) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Self::new(BufWriter::new(file), pixel_format, width, height)
+    }
+}
+
+impl<W: Write + Seek> FmfWriter<W> {
+    pub fn new(mut writer: W, pixel_format: PixelFormat, width: u32, height: u32) -> Result<Self> {
+        let (format, bits_per_pixel) = fmf_format(pixel_format);
+        let frame_bytes = width as u64 * height as u64 * pixel_format.bytes_per_pixel() as u64;
+
+        writer.write_all(&FMF_VERSION.to_le_bytes())?;
+        writer.write_all(&(format.len() as u32).to_le_bytes())?;
+        writer.write_all(format.as_bytes())?;
+        writer.write_all(&bits_per_pixel.to_le_bytes())?;
+        writer.write_all(&height.to_le_bytes())?;
+        writer.write_all(&width.to_le_bytes())?;
+        writer.write_all(&(8 + frame_bytes).to_le_bytes())?;
+        let n_frames_pos = writer.stream_position()?;
+        // filled in by `finish`; readers count the frames of a movie left at 0
+        writer.write_all(&0u64.to_le_bytes())?;
+
+        Ok(Self {
+            writer,
+            pixel_format,
+            width,
+            height,
+            n_frames: 0,
+            n_frames_pos,
+        })
+    }
+
+    /// Append `image`, taken at `timestamp` seconds.
+    pub fn write(&mut self, image: &ImageData, timestamp: f64) -> Result<()> {
+        check_frame(image, self.pixel_format, self.width, self.height)?;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        if image.pixel_format == PixelFormat::Rgb24 {
+            self.writer.write_all(&swap_red_blue(&image.data))?;
+        } else {
+            self.writer.write_all(&image.data)?;
+        }
+        self.n_frames += 1;
+        Ok(())
+    }
+
+    /// Store the frame count in the header and flush.
+    pub fn finish(mut self) -> Result<W> {
+        let end = self.writer.stream_position()?;
+        self.writer.seek(SeekFrom::Start(self.n_frames_pos))?;
+        self.writer.write_all(&self.n_frames.to_le_bytes())?;
+        self.writer.seek(SeekFrom::Start(end))?;
+        self.writer.flush()?;
+        Ok(self.writer)
+    }
+}
+
+/// Reads the frames of a FlyMovieFormat v3 movie.
+///
+/// FMF only knows `MONO16`, so clips of 10- and 12-bit cameras come back
+/// as `PixelFormat::Mono16` with their raw values unchanged.
+pub struct FmfReader<R: Read + Seek> {
+    reader: R,
+    pixel_format: PixelFormat,
+    width: u32,
+    height: u32,
+    /// Size of a timestamp and frame
+    bytes_per_chunk: u64,
+    /// Where the first frame starts
+    data_start: u64,
+    n_frames: u64,
+}
+
+impl FmfReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::new(BufReader::new(file))
+            .with_context(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+impl<R: Read + Seek> FmfReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        let version = u32::from_le_bytes(read_array(&mut reader)?);
+        if version != FMF_VERSION {
+            bail!("Unsupported FMF version {}", version);
+        }
+        let format_len = u32::from_le_bytes(read_array(&mut reader)?);
+        let format = String::from_utf8(read_bytes(&mut reader, format_len as usize)?)
+            .context("FMF format is not text")?;
+        let bits_per_pixel = u32::from_le_bytes(read_array(&mut reader)?);
+        let height = u32::from_le_bytes(read_array(&mut reader)?);
+        let width = u32::from_le_bytes(read_array(&mut reader)?);
+        let bytes_per_chunk = u64::from_le_bytes(read_array(&mut reader)?);
+        let mut n_frames = u64::from_le_bytes(read_array(&mut reader)?);
+
+        let pixel_format = pixel_format_from_fmf(&format, bits_per_pixel)?;
+        let frame_bytes = width as u64 * height as u64 * pixel_format.bytes_per_pixel() as u64;
+        if bytes_per_chunk != 8 + frame_bytes {
+            bail!(
+                "Frames of {} bytes do not match {}x{} {}",
+                bytes_per_chunk,
+                width,
+                height,
+                format
+            );
+        }
+        let data_start = reader.stream_position()?;
+        if n_frames == 0 {
+            // the count is only written once the movie is closed
+            let end = reader.seek(SeekFrom::End(0))?;
+            n_frames = (end - data_start) / bytes_per_chunk;
+            reader.seek(SeekFrom::Start(data_start))?;
+        }
+
+        Ok(Self {
+            reader,
+            pixel_format,
+            width,
+            height,
+            bytes_per_chunk,
+            data_start,
+            n_frames,
+        })
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height
+    }
+
+    pub fn n_frames(&self) -> u64 {
+        self.n_frames
+    }
+
+    /// Continue reading at frame `index`.
+    pub fn seek_frame(&mut self, index: u64) -> Result<()> {
+        if index > self.n_frames {
+            bail!("Frame {} is past the {} frames", index, self.n_frames);
+        }
+        self.reader.seek(SeekFrom::Start(
+            self.data_start + index * self.bytes_per_chunk,
+        ))?;
+        Ok(())
+    }
+
+    /// Read the next frame and its timestamp in seconds, `None` after the
+    /// last one. The frame's `nframe` is its index in the movie.
+    pub fn read_frame(&mut self) -> Result<Option<(f64, ImageData)>> {
+        let index = (self.reader.stream_position()? - self.data_start) / self.bytes_per_chunk;
+        if index >= self.n_frames {
+            return Ok(None);
+        }
+        let timestamp = f64::from_le_bytes(read_array(&mut self.reader)?);
+        let mut data = read_bytes(&mut self.reader, (self.bytes_per_chunk - 8) as usize)?;
+        if self.pixel_format == PixelFormat::Rgb24 {
+            data = swap_red_blue(&data);
+        }
+        let image = ImageData {
+            data,
+            pixel_format: self.pixel_format,
+            width: self.width,
+            height: self.height,
+            nframe: index as u32,
+            timestamp_raw: (timestamp * 1e9).round() as u64,
+            ..ImageData::default()
+        };
+        Ok(Some((timestamp, image)))
+    }
+}
+
+/// Write a clip as an FMF movie, timestamped with the camera's clock.
+pub fn write_fmf(images: &VecDeque<Arc<ImageData>>, path: &Path) -> Result<()> {
+    let first = images.front().context("No frames provided")?;
+    let mut writer = FmfWriter::create(path, first.pixel_format, first.width, first.height)?;
+    for image in images {
+        writer.write(image, timestamp_s(image))?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+/// How `ClipFormat::Ufmf` cuts the moving parts of a frame from the
+/// background.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct UfmfConfig {
+    /// Difference from the background, in gray levels, above which a pixel
+    /// is stored
+    pub threshold: u8,
+    /// Side of the square tiles regions are made of, in pixels
+    pub region_size: u16,
+}
+
+impl Default for UfmfConfig {
+    fn default() -> Self {
+        Self {
+            threshold: 15,
+            region_size: 32,
+        }
+    }
+}
+
+impl UfmfConfig {
+    pub fn validate(&self) -> Result<(), String> {
+        // even tiles keep Bayer regions aligned to the mosaic
+        if self.region_size == 0 || !self.region_size.is_multiple_of(2) {
+            return Err("uFMF region size must be a positive even number".to_string());
+        }
+        Ok(())
+    }
+}
+
+/// A rectangle of a frame stored in a uFMF movie.
+#[derive(Debug, Clone)]
+pub struct Region {
+    pub x: u16,
+    pub y: u16,
+    pub width: u16,
+    pub height: u16,
+    /// Pixels row by row
+    pub data: Vec<u8>,
+}
+
+/// Locations and timestamps of the chunks of one kind, for the index.
+type IndexEntries = Vec<(u64, f64)>;
+
+/// Writes uFMF v3: keyframes holding a whole frame, such as the background,
+/// and frames holding only regions that differ from it. 8-bit formats only.
+pub struct UfmfWriter<W: Write + Seek> {
+    writer: W,
+    pixel_format: PixelFormat,
+    /// Where the header starts, to fill it in on `finish`
+    header_pos: u64,
+    /// Largest region width and height, stored in the header
+    max_region: (u16, u16),
+    keyframes: Vec<(String, IndexEntries)>,
+    frames: IndexEntries,
+}
+
+impl UfmfWriter<BufWriter<File>> {
+    pub fn create(path: &Path, pixel_format: PixelFormat) -> Result<Self> {
+        let file =
+            File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+        Self::new(BufWriter::new(file), pixel_format)
+    }
+}
+
+impl<W: Write + Seek> UfmfWriter<W> {
+    pub fn new(mut writer: W, pixel_format: PixelFormat) -> Result<Self> {
+        if pixel_format.bytes_per_pixel() != 1 {
+            bail!("uFMF stores 8-bit frames only, not {:?}", pixel_format);
+        }
+        let (coding, _) = fmf_format(pixel_format);
+        let header_pos = writer.stream_position()?;
+        writer.write_all(b"ufmf")?;
+        writer.write_all(&UFMF_VERSION.to_le_bytes())?;
+        // index location and largest region, filled in by `finish`
+        writer.write_all(&0u64.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&0u16.to_le_bytes())?;
+        writer.write_all(&[coding.len() as u8])?;
+        writer.write_all(coding.as_bytes())?;
+
+        Ok(Self {
+            writer,
+            pixel_format,
+            header_pos,
+            max_region: (0, 0),
+            keyframes: Vec::new(),
+            frames: Vec::new(),
+        })
+    }
+
+    /// Store the whole of `image` as a keyframe of type `kind`, e.g. the
+    /// `mean` background.
+    pub fn add_keyframe(&mut self, kind: &str, image: &ImageData, timestamp: f64) -> Result<()> {
+        check_frame(image, self.pixel_format, image.width, image.height)?;
+        let (Ok(width), Ok(height)) = (u16::try_from(image.width), u16::try_from(image.height))
+        else {
+            bail!("uFMF frames are at most 65535x65535");
+        };
+        let Ok(kind_len) = u8::try_from(kind.len()) else {
+            bail!("Keyframe type {} is too long", kind);
+        };
+
+        let loc = self.writer.stream_position()?;
+        self.writer.write_all(&[KEYFRAME_CHUNK, kind_len])?;
+        self.writer.write_all(kind.as_bytes())?;
+        // dtype of the pixels, as numpy names unsigned bytes
+        self.writer.write_all(b"B")?;
+        self.writer.write_all(&width.to_le_bytes())?;
+        self.writer.write_all(&height.to_le_bytes())?;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer.write_all(&image.data)?;
+
+        match self.keyframes.iter_mut().find(|(name, _)| name == kind) {
+            Some((_, entries)) => entries.push((loc, timestamp)),
+            None => self
+                .keyframes
+                .push((kind.to_string(), vec![(loc, timestamp)])),
+        }
+        Ok(())
+    }
+
+    /// Store a frame taken at `timestamp` seconds as the `regions` that
+    /// differ from the background.
+    pub fn add_frame(&mut self, timestamp: f64, regions: &[Region]) -> Result<()> {
+        let loc = self.writer.stream_position()?;
+        self.writer.write_all(&[FRAME_CHUNK])?;
+        self.writer.write_all(&timestamp.to_le_bytes())?;
+        self.writer
+            .write_all(&(regions.len() as u32).to_le_bytes())?;
+        for region in regions {
+            if region.data.len() != region.width as usize * region.height as usize {
+                bail!(
+                    "Region at {},{} has {} bytes, expected {}x{}",
+                    region.x,
+                    region.y,
+                    region.data.len(),
+                    region.width,
+                    region.height
+                );
+            }
+            for value in [region.x, region.y, region.width, region.height] {
+                self.writer.write_all(&value.to_le_bytes())?;
+            }
+            self.writer.write_all(&region.data)?;
+            self.max_region.0 = self.max_region.0.max(region.width);
+            self.max_region.1 = self.max_region.1.max(region.height);
+        }
+        self.frames.push((loc, timestamp));
+        Ok(())
+    }
+
+    /// Write the index of keyframes and frames, point the header at it and
+    /// flush.
+    pub fn finish(mut self) -> Result<W> {
+        let index_loc = self.writer.stream_position()?;
+        let w = &mut self.writer;
+        w.write_all(&[INDEX_CHUNK, b'd', 2])?;
+        write_index_key(w, "keyframe")?;
+        w.write_all(&[b'd', self.keyframes.len() as u8])?;
+        for (kind, entries) in &self.keyframes {
+            write_index_key(w, kind)?;
+            write_index_entries(w, entries)?;
+        }
+        write_index_key(w, "frame")?;
+        write_index_entries(w, &self.frames)?;
+
+        let end = w.stream_position()?;
+        w.seek(SeekFrom::Start(self.header_pos + 8))?;
+        w.write_all(&index_loc.to_le_bytes())?;
+        w.write_all(&self.max_region.0.to_le_bytes())?;
+        w.write_all(&self.max_region.1.to_le_bytes())?;
+        w.seek(SeekFrom::Start(end))?;
+        w.flush()?;
+        Ok(self.writer)
+    }
+}
+
+fn write_index_key(writer: &mut impl Write, key: &str) -> io::Result<()> {
+    writer.write_all(&(key.len() as u16).to_le_bytes())?;
+    writer.write_all(key.as_bytes())
+}
+
+/// Write `entries` as the index dictionary of `loc` and `timestamp` arrays.
+fn write_index_entries(writer: &mut impl Write, entries: &IndexEntries) -> io::Result<()> {
+    let len = (entries.len() * 8) as u32;
+    writer.write_all(&[b'd', 2])?;
+    write_index_key(writer, "loc")?;
+    writer.write_all(b"aQ")?;
+    writer.write_all(&len.to_le_bytes())?;
+    for (loc, _) in entries {
+        writer.write_all(&loc.to_le_bytes())?;
+    }
+    write_index_key(writer, "timestamp")?;
+    writer.write_all(b"ad")?;
+    writer.write_all(&len.to_le_bytes())?;
+    for (_, timestamp) in entries {
+        writer.write_all(&timestamp.to_le_bytes())?;
+    }
+    Ok(())
+}
+
+/// A value of the uFMF index: a dictionary, or an array read as `f64`.
+enum IndexValue {
+    Dict(Vec<(String, IndexValue)>),
+    Array(Vec<f64>),
+}
+
+impl IndexValue {
+    fn get(&self, key: &str) -> Option<&IndexValue> {
+        match self {
+            IndexValue::Dict(entries) => entries
+                .iter()
+                .find(|(name, _)| name == key)
+                .map(|(_, value)| value),
+            IndexValue::Array(_) => None,
+        }
+    }
+
+    fn read(reader: &mut impl Read) -> Result<Self> {
+        match read_array::<1>(reader)?[0] {
+            b'd' => {
+                let [n] = read_array(reader)?;
+                let mut entries = Vec::with_capacity(n as usize);
+                for _ in 0..n {
+                    let len = u16::from_le_bytes(read_array(reader)?);
+                    let key = String::from_utf8(read_bytes(reader, len as usize)?)
+                        .context("uFMF index key is not text")?;
+                    entries.push((key, Self::read(reader)?));
+                }
+                Ok(IndexValue::Dict(entries))
+            }
+            b'a' => {
+                let [dtype] = read_array(reader)?;
+                let len = u32::from_le_bytes(read_array(reader)?);
+                let bytes = read_bytes(reader, len as usize)?;
+                let values = bytes.chunks_exact(8).map(|b| b.try_into().unwrap());
+                Ok(IndexValue::Array(match dtype {
+                    b'd' => values.map(f64::from_le_bytes).collect(),
+                    b'Q' => values.map(|b| u64::from_le_bytes(b) as f64).collect(),
+                    b'q' => values.map(|b| i64::from_le_bytes(b) as f64).collect(),
+                    _ => bail!("Unsupported uFMF index array type {}", dtype as char),
+                }))
+            }
+            kind => bail!("Unknown uFMF index value {}", kind as char),
+        }
+    }
+}
+
+/// Reads the frames of a uFMF v3 movie, drawing each frame's regions onto
+/// the latest `mean` keyframe.
+pub struct UfmfReader<R: Read + Seek> {
+    reader: R,
+    pixel_format: PixelFormat,
+    /// Where the frames end, unless the movie was not closed
+    index_loc: Option<u64>,
+    /// Timestamps of the frames from the index
+    timestamps: Vec<f64>,
+    background: Option<ImageData>,
+    next_frame: u32,
+}
+
+impl UfmfReader<BufReader<File>> {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("Failed to open {}", path.display()))?;
+        Self::new(BufReader::new(file))
+            .with_context(|| format!("Failed to read {}", path.display()))
+    }
+}
+
+impl<R: Read + Seek> UfmfReader<R> {
+    pub fn new(mut reader: R) -> Result<Self> {
+        if &read_array::<4>(&mut reader)? != b"ufmf" {
+            bail!("Not a uFMF file");
+        }
+        let version = u32::from_le_bytes(read_array(&mut reader)?);
+        if version != UFMF_VERSION {
+            bail!("Unsupported uFMF version {}", version);
+        }
+        let index_loc = u64::from_le_bytes(read_array(&mut reader)?);
+        // largest region size, not needed to read the regions
+        read_array::<4>(&mut reader)?;
+        let [coding_len] = read_array(&mut reader)?;
+        let coding = String::from_utf8(read_bytes(&mut reader, coding_len as usize)?)
+            .context("uFMF coding is not text")?;
+        let pixel_format = pixel_format_from_fmf(&coding, 8)?;
+
+        let index_loc = (index_loc != 0).then_some(index_loc);
+        let mut timestamps = Vec::new();
+        if let Some(index_loc) = index_loc {
+            let frames_start = reader.stream_position()?;
+            reader.seek(SeekFrom::Start(index_loc))?;
+            if read_array::<1>(&mut reader)? != [INDEX_CHUNK] {
+                bail!("uFMF index location does not point to the index");
+            }
+            let index = IndexValue::read(&mut reader)?;
+            if let Some(IndexValue::Array(values)) =
+                index.get("frame").and_then(|frame| frame.get("timestamp"))
+            {
+                timestamps.clone_from(values);
+            }
+            reader.seek(SeekFrom::Start(frames_start))?;
+        }
+
+        Ok(Self {
+            reader,
+            pixel_format,
+            index_loc,
+            timestamps,
+            background: None,
+            next_frame: 0,
+        })
+    }
+
+    pub fn pixel_format(&self) -> PixelFormat {
+        self.pixel_format
+    }
+
+    /// Timestamps of every frame, from the index. Empty if the movie was
+    /// not closed.
+    pub fn timestamps(&self) -> &[f64] {
+        &self.timestamps
+    }
+
+    /// The background the next frame is drawn onto, once a keyframe was
+    /// read.
+    pub fn background(&self) -> Option<&ImageData> {
+        self.background.as_ref()
+    }
+
+    /// Read the next frame and its timestamp in seconds, `None` after the
+    /// last one. The frame's `nframe` is its index in the movie.
+    pub fn read_frame(&mut self) -> Result<Option<(f64, ImageData)>> {
+        loop {
+            if self
+                .index_loc
+                .is_some_and(|loc| self.reader.stream_position().is_ok_and(|pos| pos >= loc))
+            {
+                return Ok(None);
+            }
+            let mut chunk = [0];
+            if self.reader.read(&mut chunk)? == 0 {
+                // a movie that was not closed ends without an index
+                return Ok(None);
+            }
+            match chunk[0] {
+                KEYFRAME_CHUNK => self.read_keyframe()?,
+                FRAME_CHUNK => return self.read_regions().map(Some),
+                INDEX_CHUNK => return Ok(None),
+                kind => bail!("Unknown uFMF chunk {}", kind),
+            }
+        }
+    }
+
+    fn read_keyframe(&mut self) -> Result<()> {
+        let [kind_len] = read_array(&mut self.reader)?;
+        let kind = read_bytes(&mut self.reader, kind_len as usize)?;
+        let [dtype] = read_array(&mut self.reader)?;
+        if dtype != b'B' {
+            bail!("Unsupported uFMF keyframe type {}", dtype as char);
+        }
+        let width = u16::from_le_bytes(read_array(&mut self.reader)?);
+        let height = u16::from_le_bytes(read_array(&mut self.reader)?);
+        let timestamp = f64::from_le_bytes(read_array(&mut self.reader)?);
+        let data = read_bytes(&mut self.reader, width as usize * height as usize)?;
+        if kind == BACKGROUND.as_bytes() {
+            self.background = Some(ImageData {
+                data,
+                pixel_format: self.pixel_format,
+                width: width.into(),
+                height: height.into(),
+                timestamp_raw: (timestamp * 1e9).round() as u64,
+                ..ImageData::default()
+            });
+        }
+        Ok(())
+    }
+
+    fn read_regions(&mut self) -> Result<(f64, ImageData)> {
+        let timestamp = f64::from_le_bytes(read_array(&mut self.reader)?);
+        let n_regions = u32::from_le_bytes(read_array(&mut self.reader)?);
+        let mut image = self
+            .background
+            .clone()
+            .context("uFMF frame before the first background keyframe")?;
+        let frame_width = image.width as usize;
+        for _ in 0..n_regions {
+            let [x, y, width, height] =
+                [(); 4].map(|()| read_array(&mut self.reader).map(u16::from_le_bytes));
+            let (x, y, width, height) =
+                (x? as usize, y? as usize, width? as usize, height? as usize);
+            let data = read_bytes(&mut self.reader, width * height)?;
+            if x + width > frame_width || y + height > image.height as usize {
+                bail!("uFMF region at {},{} is outside the frame", x, y);
+            }
+            for (row, pixels) in data.chunks_exact(width.max(1)).enumerate() {
+                let start = (y + row) * frame_width + x;
+                image.data[start..start + width].copy_from_slice(pixels);
+            }
+        }
+        image.nframe = self.next_frame;
+        image.timestamp_raw = (timestamp * 1e9).round() as u64;
+        self.next_frame += 1;
+        Ok((timestamp, image))
+    }
+}
+
+/// Per-pixel median of up to `BACKGROUND_FRAMES` frames spread over the
+/// clip, so an object moving through the clip is not part of it.
+fn median_background(images: &VecDeque<Arc<ImageData>>) -> Option<ImageData> {
+    let first = images.front()?;
+    let step = images.len().div_ceil(BACKGROUND_FRAMES);
+    let samples: Vec<&[u8]> = images
+        .iter()
+        .step_by(step)
+        .map(|image| image.data.as_slice())
+        .collect();
+    let mut values = vec![0; samples.len()];
+    let data = (0..first.data.len())
+        .map(|i| {
+            for (value, sample) in values.iter_mut().zip(&samples) {
+                *value = sample[i];
+            }
+            let middle = values.len() / 2;
+            *values.select_nth_unstable(middle).1
+        })
+        .collect();
+    Some(ImageData {
+        data,
+        pixel_format: first.pixel_format,
+        width: first.width,
+        height: first.height,
+        timestamp_raw: first.timestamp_raw,
+        ..ImageData::default()
+    })
+}
+
+/// Tiles of `image` with a pixel differing from `background` by more than
+/// `config.threshold`, neighbouring tiles of a row merged into one region.
+fn foreground_regions(
+    image: &ImageData,
+    background: &ImageData,
+    config: &UfmfConfig,
+) -> Vec<Region> {
+    let (width, height) = (image.width as usize, image.height as usize);
+    let size = config.region_size as usize;
+    let differs = |x: usize, y: usize, w: usize, h: usize| {
+        (y..y + h).any(|row| {
+            let pixels = row * width + x..row * width + x + w;
+            image.data[pixels.clone()]
+                .iter()
+                .zip(&background.data[pixels])
+                .any(|(a, b)| a.abs_diff(*b) > config.threshold)
+        })
+    };
+    let crop = |x: usize, y: usize, w: usize, h: usize| Region {
+        x: x as u16,
+        y: y as u16,
+        width: w as u16,
+        height: h as u16,
+        data: (y..y + h)
+            .flat_map(|row| &image.data[row * width + x..row * width + x + w])
+            .copied()
+            .collect(),
+    };
+
+    let mut regions = Vec::new();
+    for y in (0..height).step_by(size) {
+        let h = size.min(height - y);
+        let mut run_start = None;
+        // the frame's width ends the last run of the row
+        for x in (0..width).step_by(size).chain(iter::once(width)) {
+            let active = x < width && differs(x, y, size.min(width - x), h);
+            match (active, run_start) {
+                (true, None) => run_start = Some(x),
+                (false, Some(start)) => {
+                    regions.push(crop(start, y, x - start, h));
+                    run_start = None;
+                }
+                _ => {}
+            }
+        }
+    }
+    regions
+}
+
+/// Write a clip as a uFMF movie: the clip's median as the background
+/// keyframe, then every frame as the regions that differ from it.
+pub fn write_ufmf(
+    images: &VecDeque<Arc<ImageData>>,
+    path: &Path,
+    config: &UfmfConfig,
+) -> Result<()> {
+    let file =
+        File::create(path).with_context(|| format!("Failed to create {}", path.display()))?;
+    encode_ufmf(BufWriter::new(file), images, config)?;
+    Ok(())
+}
+
+fn encode_ufmf<W: Write + Seek>(
+    writer: W,
+    images: &VecDeque<Arc<ImageData>>,
+    config: &UfmfConfig,
+) -> Result<W> {
+    let first = images.front().context("No frames provided")?;
+    let background = median_background(images).context("No frames provided")?;
+    let mut writer = UfmfWriter::new(writer, first.pixel_format)?;
+    writer.add_keyframe(BACKGROUND, &background, timestamp_s(first))?;
+    for image in images {
+        check_frame(
+            image,
+            background.pixel_format,
+            background.width,
+            background.height,
+        )?;
+        let regions = foreground_regions(image, &background, config);
+        writer.add_frame(timestamp_s(image), &regions)?;
+    }
+    writer.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    /// `n` frames of a pattern that differs between frames and pixels.
+    fn frames(pixel_format: PixelFormat, width: u32, height: u32, n: u32) -> Vec<ImageData> {
+        let len = width as usize * height as usize * pixel_format.bytes_per_pixel();
+        (0..n)
+            .map(|nframe| ImageData {
+                data: (0..len)
+                    .map(|i| (i * 7 + nframe as usize * 13) as u8)
+                    .collect(),
+                pixel_format,
+                width,
+                height,
+                nframe,
+                timestamp_raw: 1_000_000_000 + nframe as u64 * 10_000_000,
+                ..ImageData::default()
+            })
+            .collect()
+    }
+
+    fn write_fmf_to_vec(images: &[ImageData]) -> (Vec<u8>, u64) {
+        let first = &images[0];
+        let mut writer = FmfWriter::new(
+            Cursor::new(Vec::new()),
+            first.pixel_format,
+            first.width,
+            first.height,
+        )
+        .unwrap();
+        for image in images {
+            writer.write(image, timestamp_s(image)).unwrap();
+        }
+        let n_frames_pos = writer.n_frames_pos;
+        (writer.finish().unwrap().into_inner(), n_frames_pos)
+    }
+
+    fn read_all<R: Read + Seek>(reader: &mut FmfReader<R>) -> Vec<(f64, ImageData)> {
+        iter::from_fn(|| reader.read_frame().unwrap()).collect()
+    }
+
+    #[test]
+    fn fmf_round_trip() {
+        for pixel_format in [
+            PixelFormat::Mono8,
+            PixelFormat::Mono16,
+            PixelFormat::BayerGrbg8,
+            PixelFormat::Rgb24,
+        ] {
+            let images = frames(pixel_format, 6, 4, 3);
+            let (bytes, _) = write_fmf_to_vec(&images);
+            let mut reader = FmfReader::new(Cursor::new(bytes)).unwrap();
+
+            assert_eq!(reader.pixel_format(), pixel_format);
+            assert_eq!((reader.width(), reader.height()), (6, 4));
+            assert_eq!(reader.n_frames(), 3);
+            let read = read_all(&mut reader);
+            assert_eq!(read.len(), images.len(), "{:?}", pixel_format);
+            for (image, (timestamp, frame)) in images.iter().zip(read) {
+                assert_eq!(timestamp, timestamp_s(image));
+                assert_eq!(frame.timestamp_raw, image.timestamp_raw);
+                assert_eq!(frame.nframe, image.nframe);
+                assert_eq!(frame.data, image.data, "{:?}", pixel_format);
+            }
+        }
+    }
+
+    #[test]
+    fn fmf_stores_color_in_red_green_blue_order() {
+        let image = ImageData {
+            data: vec![1, 2, 3],
+            pixel_format: PixelFormat::Rgb24,
+            width: 1,
+            height: 1,
+            ..ImageData::default()
+        };
+        let (bytes, _) = write_fmf_to_vec(&[image]);
+        assert_eq!(bytes[bytes.len() - 3..], [3, 2, 1]);
+    }
+
+    #[test]
+    fn fmf_deep_mono_reads_back_as_mono16() {
+        let images = frames(PixelFormat::Mono12, 4, 2, 2);
+        let (bytes, _) = write_fmf_to_vec(&images);
+        let mut reader = FmfReader::new(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(reader.pixel_format(), PixelFormat::Mono16);
+        let read = read_all(&mut reader);
+        assert_eq!(read.len(), 2);
+        for (image, (_, frame)) in images.iter().zip(read) {
+            assert_eq!(frame.data, image.data);
+        }
+    }
+
+    #[test]
+    fn fmf_frame_count() {
+        let images = frames(PixelFormat::Mono8, 5, 3, 4);
+        let (mut bytes, n_frames_pos) = write_fmf_to_vec(&images);
+        let pos = n_frames_pos as usize;
+        assert_eq!(bytes[pos..pos + 8], 4u64.to_le_bytes());
+
+        // a movie that was not closed has no count, the frames are counted
+        bytes[pos..pos + 8].copy_from_slice(&0u64.to_le_bytes());
+        let mut reader = FmfReader::new(Cursor::new(bytes)).unwrap();
+        assert_eq!(reader.n_frames(), 4);
+        assert_eq!(read_all(&mut reader).len(), 4);
+    }
+
+    #[test]
+    fn fmf_seek_frame() {
+        let images = frames(PixelFormat::Mono8, 5, 3, 4);
+        let (bytes, _) = write_fmf_to_vec(&images);
+        let mut reader = FmfReader::new(Cursor::new(bytes)).unwrap();
+
+        reader.seek_frame(2).unwrap();
+        let (_, frame) = reader.read_frame().unwrap().unwrap();
+        assert_eq!(frame.nframe, 2);
+        assert_eq!(frame.data, images[2].data);
+        assert!(reader.seek_frame(5).is_err());
+    }
+
+    #[test]
+    fn fmf_rejects_mismatched_frames() {
+        let mut writer = FmfWriter::new(Cursor::new(Vec::new()), PixelFormat::Mono8, 4, 4).unwrap();
+        let image = &frames(PixelFormat::Mono8, 4, 3, 1)[0];
+        assert!(writer.write(image, 0.0).is_err());
+    }
+
+    /// Frames of a gradient with a bright square moving across it.
+    fn moving_square(width: u32, height: u32, n: u32) -> VecDeque<Arc<ImageData>> {
+        (0..n)
+            .map(|nframe| {
+                let (left, top) = (3 + nframe as usize * 11, 2 + nframe as usize * 5);
+                let data = (0..height as usize)
+                    .flat_map(|y| (0..width as usize).map(move |x| (x, y)))
+                    .map(|(x, y)| {
+                        let inside = (left..left + 6).contains(&x) && (top..top + 6).contains(&y);
+                        if inside {
+                            200 + (x + y) as u8 % 50
+                        } else {
+                            (x + 2 * y) as u8 % 100
+                        }
+                    })
+                    .collect();
+                Arc::new(ImageData {
+                    data,
+                    pixel_format: PixelFormat::Mono8,
+                    width,
+                    height,
+                    nframe,
+                    timestamp_raw: 2_000_000_000 + nframe as u64 * 5_000_000,
+                    ..ImageData::default()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn ufmf_round_trip() {
+        // a width and height that are not a multiple of the tiles
+        let images = moving_square(70, 45, 5);
+        let config = UfmfConfig {
+            region_size: 16,
+            ..UfmfConfig::default()
+        };
+        let bytes = encode_ufmf(Cursor::new(Vec::new()), &images, &config)
+            .unwrap()
+            .into_inner();
+        let mut reader = UfmfReader::new(Cursor::new(bytes)).unwrap();
+
+        assert_eq!(reader.pixel_format(), PixelFormat::Mono8);
+        let expected: Vec<f64> = images.iter().map(|image| timestamp_s(image)).collect();
+        assert_eq!(reader.timestamps(), expected);
+        let read: Vec<_> = iter::from_fn(|| reader.read_frame().unwrap()).collect();
+        assert_eq!(read.len(), images.len());
+        for (image, (timestamp, frame)) in images.iter().zip(read) {
+            assert_eq!(timestamp, timestamp_s(image));
+            assert_eq!(frame.nframe, image.nframe);
+            assert_eq!(frame.timestamp_raw, image.timestamp_raw);
+            assert_eq!((frame.width, frame.height), (70, 45));
+            assert_eq!(frame.data, image.data, "frame {}", image.nframe);
+        }
+    }
+
+    #[test]
+    fn ufmf_stores_only_the_moving_parts() {
+        let images = moving_square(70, 45, 5);
+        let config = UfmfConfig {
+            region_size: 16,
+            ..UfmfConfig::default()
+        };
+        let background = median_background(&images).unwrap();
+        // the square covers 6x6 pixels, at most 2x2 tiles
+        for image in &images {
+            let regions = foreground_regions(image, &background, &config);
+            let pixels: usize = regions.iter().map(|region| region.data.len()).sum();
+            assert!(!regions.is_empty());
+            assert!(pixels <= 4 * 16 * 16, "frame {}", image.nframe);
+        }
+        assert!(foreground_regions(&background, &background, &config).is_empty());
+    }
+
+    #[test]
+    fn ufmf_rejects_deep_frames() {
+        assert!(UfmfWriter::new(Cursor::new(Vec::new()), PixelFormat::Mono16).is_err());
+    }
+}
@@ -1,41 +1,169 @@
 // Standard library imports
-use crate::{
-    structs::{FramesPacket, ImageData, MessageType},
-    KalmanEstimateRow,
+use crate::archive::{save_image, write_raw, write_sequence};
+use crate::config::{ClipFormat, OverlapPolicy, RecordingConfig};
+use crate::control_api::ControlCommand;
+use crate::encoders::{EncodeSettings, Encoder, VideoEncoder};
+use crate::events::{CameraStatus, Event, EventPublisher};
+use crate::fmf::{write_fmf, write_ufmf, UfmfConfig};
+use crate::helpers::disk_free;
+use crate::settings::AppliedCameraSettings;
+use crate::structs::{
+    ClipMetadata, ClipTrigger, FrameStamp, FramesPacket, ImageData, KalmanEstimateRow, MessageType,
+    PixelFormat, TriggerSource, TriggerTime,
 };
-use anyhow::{Context, Result};
-use crossbeam::channel::{unbounded, Receiver};
+use crate::templates::{PathTemplate, TriggerContext};
+use anyhow::{anyhow, Context, Result};
+use chrono::Local;
+use crossbeam::channel::{unbounded, Receiver, RecvTimeoutError, Sender};
+use serde_json::json;
 use std::{
     collections::VecDeque,
     fs::{create_dir_all, OpenOptions},
     io::Write,
     path::{Path, PathBuf},
-    process::{Command, Stdio},
     sync::Arc,
     thread,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
-fn save_video_metadata(images: &VecDeque<Arc<ImageData>>, save_path: &Path) -> Result<()> {
-    log::debug!("Saving metadata to disk");
+/// Where and how much one camera records around each trigger.
+#[derive(Clone)]
+pub struct ClipOutput {
+    pub n_before: usize,
+    pub n_after: usize,
+    /// Longest manual or extended recording in frames after the pre-trigger
+    /// frames, after which it is saved
+    pub n_max: usize,
+    /// Times the frame counts are computed from when the framerate changes
+    pub recording: RecordingConfig,
+    /// Most bytes of frame data a clip holds before it is saved
+    pub max_clip_bytes: usize,
+    /// What to do with a trigger arriving while a clip is being recorded
+    pub overlap: OverlapPolicy,
+    /// Minimum time between clips with `OverlapPolicy::Refractory`
+    pub refractory: Duration,
+    /// Most clips recording or queued for the writer at once
+    pub max_pending_clips: usize,
+    pub save_folder: PathBuf,
+    pub template: PathTemplate,
+    /// Serial number, or a generated name for cameras opened without one
+    pub camera_serial: String,
+    pub format: ClipFormat,
+    /// Encoder picked at startup for `ClipFormat::Video`
+    pub encoder: Option<Encoder>,
+    pub ufmf: UfmfConfig,
+    /// Configured framerate, used when the frames carry no timestamps
+    pub fps: f32,
+    /// Framerate written to the container instead of the acquisition rate,
+    /// to play clips back in slow motion
+    pub playback_fps: Option<f32>,
+}
+
+impl ClipOutput {
+    /// Frames buffered for the next clip: the pre-trigger frames, and those
+    /// that may have been taken after a trigger delivered late.
+    fn n_buffered(&self) -> usize {
+        self.n_before + (self.fps * Clip::LOOKBACK.as_secs_f32()).ceil() as usize
+    }
+
+    /// Recompute the frame counts for a new framerate. Clips being recorded
+    /// keep the frames they have, and later frames are counted at `fps`.
+    pub fn set_fps(&mut self, fps: f32) {
+        (self.n_before, self.n_after) = self.recording.frames(fps);
+        self.n_max = self.recording.max_frames(fps);
+        self.fps = fps;
+    }
+
+    /// How frames of `pixel_format` are encoded, for the formats stored as
+    /// video.
+    fn encode_settings(&self, pixel_format: PixelFormat) -> Result<Option<EncodeSettings>> {
+        Ok(match self.format {
+            ClipFormat::Video => {
+                let encoder = self.encoder.as_ref().context("No encoder was selected")?;
+                Some(encoder.settings(pixel_format))
+            }
+            ClipFormat::Ffv1 => Some(EncodeSettings::ffv1(pixel_format)),
+            ClipFormat::Raw
+            | ClipFormat::Tiff
+            | ClipFormat::Png
+            | ClipFormat::Fmf
+            | ClipFormat::Ufmf => None,
+        })
+    }
+
+    /// File, or directory for image sequences, the frames of the clip at
+    /// `save_path` are written to.
+    fn frames_path(&self, save_path: &Path, pixel_format: PixelFormat) -> Result<PathBuf> {
+        let extension = match self.format {
+            ClipFormat::Raw => "bin",
+            ClipFormat::Fmf => "fmf",
+            ClipFormat::Ufmf => "ufmf",
+            ClipFormat::Tiff | ClipFormat::Png => return Ok(save_path.to_path_buf()),
+            ClipFormat::Video | ClipFormat::Ffv1 => {
+                self.encode_settings(pixel_format)?
+                    .context("No encoder was selected")?
+                    .extension
+            }
+        };
+        Ok(with_suffix(save_path, &format!(".{}", extension)))
+    }
+}
+
+/// Append `suffix` to the file name. Unlike `Path::with_extension` this
+/// keeps rendered values that contain dots, such as `{x:.2}`.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
 
-    let mut save_path_str = save_path.to_string_lossy().to_string();
-    save_path_str.push_str(".csv");
+/// Add `_1`, `_2`, ... to `save_path` until no earlier clip uses the name.
+///
+/// Every clip starts with its metadata CSV, so that is what is checked.
+fn unique_path(save_path: PathBuf) -> PathBuf {
+    let taken = |path: &Path| path.exists() || with_suffix(path, ".csv").exists();
+    if !taken(&save_path) {
+        return save_path;
+    }
+    (1..)
+        .map(|i| with_suffix(&save_path, &format!("_{}", i)))
+        .find(|path| !taken(path))
+        .expect("unbounded range")
+}
+
+fn save_video_metadata(
+    images: &VecDeque<Arc<ImageData>>,
+    timing: &ClipTiming,
+    video_fps: f64,
+    save_path: &Path,
+) -> Result<()> {
+    log::debug!("Saving metadata to disk");
 
-    let new_path: &Path = Path::new(&save_path_str);
+    let new_path = with_suffix(save_path, ".csv");
+    if let Some(parent) = new_path.parent() {
+        create_dir_all(parent).context("Failed to create clip directory")?;
+    }
 
     let mut file = OpenOptions::new()
         .create(true)
         .append(true)
-        .open(new_path)
+        .open(&new_path)
         .context("Failed to open metadata file")?;
 
-    writeln!(file, "nframe,acq_nframe,timestamp_raw,exposure_time")?;
+    writeln!(
+        file,
+        "nframe,acq_nframe,timestamp_raw,exposure_time,pts,pts_s"
+    )?;
 
-    for image in images.iter() {
+    for (image, slot) in images.iter().zip(&timing.slots) {
         let line = format!(
-            "{},{},{},{}",
-            image.nframe, image.acq_nframe, image.timestamp_raw, image.exposure_time,
+            "{},{},{},{},{},{:.6}",
+            image.nframe,
+            image.acq_nframe,
+            image.timestamp_raw,
+            image.exposure_time,
+            slot,
+            *slot as f64 / video_fps,
         );
         writeln!(file, "{}", line)?;
     }
@@ -43,93 +171,554 @@ fn save_video_metadata(images: &VecDeque<Arc<ImageData>>, save_path: &Path) -> R
     Ok(())
 }
 
-fn video_writer(rx: Receiver<FramesPacket>) -> Result<()> {
+/// Store what triggered the clip next to it.
+fn save_clip_metadata(metadata: &ClipMetadata, save_path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(with_suffix(save_path, ".meta.json"))
+        .context("Failed to open clip metadata file")?;
+    serde_json::to_writer_pretty(file, metadata).context("Failed to write clip metadata")?;
+
+    Ok(())
+}
+
+/// Store the settings the camera actually ran with next to the clip.
+fn save_camera_settings(settings: &AppliedCameraSettings, save_path: &Path) -> Result<()> {
+    let file = OpenOptions::new()
+        .create(true)
+        .write(true)
+        .truncate(true)
+        .open(with_suffix(save_path, ".settings.json"))
+        .context("Failed to open camera settings file")?;
+    serde_json::to_writer_pretty(file, settings).context("Failed to write camera settings")?;
+
+    Ok(())
+}
+
+/// Save a single frame as PNG. Deeper formats keep their raw values as
+/// 16-bit gray, Bayer frames are saved as the 8-bit mosaic.
+pub fn save_snapshot(image: &ImageData, path: &Path) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        create_dir_all(parent).context("Failed to create snapshot directory")?;
+    }
+    save_image(image, path).with_context(|| format!("Failed to save snapshot {}", path.display()))
+}
+
+/// Save clips until the kill packet arrives, publishing an event when each
+/// clip is started, saved or failed. A failed clip does not stop the writer.
+fn video_writer(rx: Receiver<FramesPacket>, output: ClipOutput, events: EventPublisher) {
+    let camera_serial = &output.camera_serial;
     while let Ok(packet) = rx.recv() {
         if packet.save_path.to_str().unwrap_or("") == "kill" {
             log::info!("Received kill signal in video writer");
             break;
         }
-        save_video_metadata(&packet.images, &packet.save_path)?;
-
-        let first_frame = packet.images.front().context("No frames provided")?;
-        let (width, height) = (first_frame.width, first_frame.height);
-        let save_path_str = packet
-            .save_path
-            .with_extension("mp4")
-            .to_str()
-            .context("Failed to convert path to string")?
-            .to_string();
-
-        log::debug!("Starting ffmpeg command to save video to {}", save_path_str);
-
-        let mut ffmpeg_command = Command::new("ffmpeg")
-            .args([
-                "-f",
-                "rawvideo",
-                "-pixel_format",
-                "gray",
-                "-video_size",
-                &format!("{}x{}", width, height),
-                "-framerate",
-                "25",
-                "-i",
-                "-",
-                "-vf",
-                "format=gray",
-                "-vcodec",
-                "h264_nvenc",
-                "-preset",
-                "p4",
-                "-tune",
-                "hq",
-                &save_path_str,
-            ])
-            .stdin(Stdio::piped())
-            .stdout(Stdio::null())
-            .spawn()
-            .context("Failed to start ffmpeg command")?;
-
-        let stdin = ffmpeg_command
-            .stdin
-            .as_mut()
-            .context("Failed to open stdin")?;
-
-        println!("Writing frames to ffmpeg");
-        for frame in packet.images {
-            stdin.write_all(&frame.data)?;
-        }
-
-        let ffmpeg_status = ffmpeg_command.wait()?;
-        log::debug!("ffmpeg command finished with status: {:?}", ffmpeg_status);
+        let save_path = unique_path(packet.save_path.clone());
+        let video_path = packet
+            .images
+            .front()
+            .and_then(|frame| output.frames_path(&save_path, frame.pixel_format).ok())
+            .unwrap_or_else(|| save_path.clone());
+        let frames = packet.images.len();
+        let metadata = packet.metadata.as_ref();
+        events.publish(Event::RecordingStarted {
+            camera: camera_serial.clone(),
+            path: video_path.clone(),
+            frames,
+            trigger_time: metadata.map(|metadata| metadata.trigger.trigger_time.clone()),
+            trigger_frame: metadata.and_then(|metadata| metadata.trigger_frame),
+        });
+
+        let timing = ClipTiming::new(&packet.images, output.fps);
+        match write_clip(&packet, &save_path, &output, &timing) {
+            Ok(()) => {
+                log::info!("Saved {} frames to {}", frames, video_path.display());
+                events.publish(Event::RecordingSaved {
+                    camera: camera_serial.clone(),
+                    path: video_path,
+                    frames,
+                    duration_s: Some(timing.duration_s()),
+                });
+            }
+            Err(e) => {
+                log::error!("Failed to save {}: {:#}", video_path.display(), e);
+                events.publish(Event::RecordingFailed {
+                    camera: camera_serial.clone(),
+                    path: video_path,
+                    error: format!("{:#}", e),
+                });
+            }
+        }
     }
+}
 
-    Ok(())
+/// Write the metadata, camera settings and frames of one clip.
+///
+/// A video has a timestamp every `1 / timing.fps` of acquisition time, and
+/// plays at that rate unless `output.playback_fps` is set. Frames dropped by
+/// the camera leave gaps in the timestamps, so the video keeps the real
+/// timing. Raw frames and image sequences are timed by the CSV alone, FMF
+/// and uFMF movies by the camera timestamp of each frame.
+fn write_clip(
+    packet: &FramesPacket,
+    save_path: &Path,
+    output: &ClipOutput,
+    timing: &ClipTiming,
+) -> Result<()> {
+    let first_frame = packet.images.front().context("No frames provided")?;
+    let settings = output.encode_settings(first_frame.pixel_format)?;
+    let video_fps = output.playback_fps.map_or(timing.fps, f64::from);
+
+    save_video_metadata(&packet.images, timing, video_fps, save_path)?;
+    if let Some(settings) = &packet.camera_settings {
+        save_camera_settings(settings, save_path)?;
+    }
+    let mut tags = vec![("fps", timing.fps.to_string())];
+    if let Some(metadata) = &packet.metadata {
+        let metadata = ClipMetadata {
+            encoder: settings.as_ref().map(|settings| settings.codec.to_string()),
+            fps: Some(timing.fps),
+            playback_fps: output.playback_fps,
+            missing_frames: timing.missing_frames(),
+            ..metadata.clone()
+        };
+        save_clip_metadata(&metadata, save_path)?;
+        tags.extend([
+            ("camera", metadata.camera.clone()),
+            (
+                "trigger_source",
+                metadata.trigger.trigger_source.to_string(),
+            ),
+            ("trigger_time", metadata.trigger.trigger_time.clone()),
+            ("comment", serde_json::to_string(&metadata)?),
+        ]);
+    }
+
+    let frames_path = output.frames_path(save_path, first_frame.pixel_format)?;
+    let Some(settings) = settings else {
+        return match output.format {
+            ClipFormat::Raw => write_raw(
+                &packet.images,
+                &frames_path,
+                &with_suffix(save_path, ".index.csv"),
+            ),
+            ClipFormat::Tiff => write_sequence(&packet.images, &frames_path, "tiff"),
+            ClipFormat::Fmf => write_fmf(&packet.images, &frames_path),
+            ClipFormat::Ufmf => write_ufmf(&packet.images, &frames_path, &output.ufmf),
+            _ => write_sequence(&packet.images, &frames_path, "png"),
+        };
+    };
+    let mut encoder = VideoEncoder::create(&frames_path, &settings, first_frame, video_fps, &tags)?;
+    for (frame, &slot) in packet.images.iter().zip(&timing.slots) {
+        encoder.write(frame, slot as i64)?;
+    }
+    encoder.finish()
+}
+
+/// Where the frames of a clip fall on the constant frame grid of its video.
+struct ClipTiming {
+    /// Acquisition framerate the grid is spaced at
+    fps: f64,
+    /// Timestamp of each frame of the clip, in frame intervals
+    slots: Vec<u64>,
+}
+
+impl ClipTiming {
+    /// Longest gap kept in the video, in seconds. Longer pauses, as with an
+    /// idle external trigger, are shortened to this.
+    const MAX_GAP_S: f64 = 1.0;
+
+    /// Place `images` by their `timestamp_raw` (ns). The framerate is the
+    /// median interval between frames, which drops do not affect, or
+    /// `nominal_fps` if the frames carry no timestamps.
+    fn new(images: &VecDeque<Arc<ImageData>>, nominal_fps: f32) -> Self {
+        let mut intervals: Vec<u64> = images
+            .iter()
+            .zip(images.iter().skip(1))
+            .map(|(a, b)| b.timestamp_raw.saturating_sub(a.timestamp_raw))
+            .filter(|interval| *interval > 0)
+            .collect();
+        intervals.sort_unstable();
+        let fps = match intervals.get(intervals.len() / 2) {
+            Some(&median) => 1e9 / median as f64,
+            None => f64::from(nominal_fps),
+        };
+
+        let max_step = (fps * Self::MAX_GAP_S).ceil().max(1.0) as u64;
+        let mut slots = Vec::with_capacity(images.len());
+        let mut previous: Option<(u64, u64)> = None;
+        for image in images {
+            let slot = match previous {
+                None => 0,
+                Some((slot, timestamp)) => {
+                    let elapsed = image.timestamp_raw.saturating_sub(timestamp) as f64 / 1e9;
+                    slot + ((elapsed * fps).round() as u64).clamp(1, max_step)
+                }
+            };
+            slots.push(slot);
+            previous = Some((slot, image.timestamp_raw));
+        }
+        Self { fps, slots }
+    }
+
+    /// Frame intervals without a frame, from frames dropped by the camera.
+    fn missing_frames(&self) -> u64 {
+        self.slots
+            .last()
+            .map_or(0, |last| last + 1 - self.slots.len() as u64)
+    }
+
+    /// Length of the clip in acquisition time.
+    fn duration_s(&self) -> f64 {
+        self.slots
+            .last()
+            .map_or(0.0, |last| (last + 1) as f64 / self.fps)
+    }
+}
+
+/// Counters behind `CameraStatus`.
+struct FrameStats {
+    frames: u64,
+    dropped: u64,
+    last_nframe: Option<u32>,
+    /// Start of the current framerate measurement and the frame count then
+    window: (Instant, u64),
+}
+
+impl FrameStats {
+    fn new() -> Self {
+        Self {
+            frames: 0,
+            dropped: 0,
+            last_nframe: None,
+            window: (Instant::now(), 0),
+        }
+    }
+
+    /// Count `image`, and the frames missing between it and the previous one.
+    fn record(&mut self, image: &ImageData) {
+        if let Some(last) = self.last_nframe {
+            self.dropped += u64::from(image.nframe.wrapping_sub(last).saturating_sub(1));
+        }
+        self.frames += 1;
+        self.last_nframe = Some(image.nframe);
+    }
+
+    /// Frames per second since the measurement window was last restarted.
+    fn fps(&self) -> f64 {
+        let (start, frames) = self.window;
+        let elapsed = start.elapsed().as_secs_f64();
+        if elapsed > 0.0 {
+            (self.frames - frames) as f64 / elapsed
+        } else {
+            0.0
+        }
+    }
+
+    fn restart_window(&mut self) {
+        self.window = (Instant::now(), self.frames);
+    }
+}
+
+/// Maps a camera's `timestamp_raw` onto the host's clock, to find the frame
+/// taken when a trigger arrived.
+///
+/// Frames reach the frame handler some time after they were taken, so the
+/// smallest difference between arrival and camera time is the best estimate
+/// of the offset between the clocks. It may grow by `DRIFT` of the camera
+/// time between frames, to follow a camera clock that runs slow.
+struct FrameClock {
+    epoch: Instant,
+    /// Host minus camera time of the least delayed frame, in nanoseconds
+    offset: Option<i64>,
+    last_timestamp: u64,
+}
+
+impl FrameClock {
+    const DRIFT: f64 = 1e-4;
+
+    fn new() -> Self {
+        Self {
+            epoch: Instant::now(),
+            offset: None,
+            last_timestamp: 0,
+        }
+    }
+
+    /// Refine the offset with `image`, which arrived at `arrival`.
+    fn record(&mut self, image: &ImageData, arrival: Instant) {
+        if image.timestamp_raw == 0 {
+            return;
+        }
+        let host = arrival.duration_since(self.epoch).as_nanos() as i64;
+        let sample = host - image.timestamp_raw as i64;
+        self.offset = Some(match self.offset {
+            Some(offset) if image.timestamp_raw >= self.last_timestamp => {
+                let elapsed = image.timestamp_raw - self.last_timestamp;
+                sample.min(offset + (elapsed as f64 * Self::DRIFT) as i64)
+            }
+            // the first frame, or the camera clock restarted
+            _ => sample,
+        });
+        self.last_timestamp = image.timestamp_raw;
+    }
+
+    /// When `image` was taken on the host's clock, `None` for cameras
+    /// without timestamps.
+    fn taken_at(&self, image: &ImageData) -> Option<Instant> {
+        let offset = self.offset.filter(|_| image.timestamp_raw != 0)?;
+        let host = (image.timestamp_raw as i64 + offset).max(0);
+        Some(self.epoch + Duration::from_nanos(host as u64))
+    }
+}
+
+/// What a clip is recorded for. Triggers without tracking data leave the
+/// Kalman fields of the template at zero.
+struct Trigger {
+    row: Option<KalmanEstimateRow>,
+    source: TriggerSource,
+    label: Option<String>,
+    time: TriggerTime,
+}
+
+impl Trigger {
+    fn new(source: TriggerSource, label: Option<String>, time: TriggerTime) -> Self {
+        Self {
+            row: None,
+            source,
+            label,
+            time,
+        }
+    }
+
+    fn tracked(row: KalmanEstimateRow, time: TriggerTime) -> Self {
+        Self {
+            row: Some(row),
+            ..Self::new(TriggerSource::Tracker, None, time)
+        }
+    }
+
+    fn metadata(&self) -> ClipTrigger {
+        ClipTrigger {
+            trigger_source: self.source,
+            label: self.label.clone(),
+            trigger_time: self.time.wallclock.to_rfc3339(),
+            kalman: self.row,
+        }
+    }
+}
+
+/// A clip collecting frames until it is complete.
+///
+/// The clip starts `n_before` frames before the first frame taken at or
+/// after its trigger arrived, which is not necessarily the frame the trigger
+/// was delivered with: frames can wait in the camera's queue, and each
+/// camera sees the trigger at its own next frame.
+struct Clip {
+    trigger: Trigger,
+    /// Later triggers merged in by `OverlapPolicy::Extend`
+    extended_by: Vec<Trigger>,
+    images: VecDeque<Arc<ImageData>>,
+    n_before: usize,
+    /// Frames still to record; `None` for manual recordings, which run until
+    /// they are stopped
+    remaining: Option<usize>,
+    /// Index in `images` of the first frame taken after the trigger, once
+    /// it arrived
+    trigger_index: Option<usize>,
+}
+
+impl Clip {
+    /// Longest a clip waits for the frame taken at its trigger, in case the
+    /// camera's clock cannot be trusted
+    const MAX_TRIGGER_DELAY: Duration = Duration::from_secs(1);
+    /// How long frames taken after a trigger can arrive before the trigger
+    /// itself is delivered
+    const LOOKBACK: Duration = Duration::from_millis(50);
+
+    /// Start with the frames buffered before the trigger, of which the
+    /// `n_before` before the trigger frame are kept.
+    fn new(
+        trigger: Trigger,
+        buffered: &VecDeque<Arc<ImageData>>,
+        n_before: usize,
+        remaining: Option<usize>,
+        clock: &FrameClock,
+    ) -> Self {
+        let mut clip = Self {
+            trigger,
+            extended_by: Vec::new(),
+            images: buffered.clone(),
+            n_before,
+            remaining,
+            trigger_index: None,
+        };
+        let instant = clip.trigger.time.instant;
+        if let Some(index) = clip
+            .images
+            .iter()
+            .position(|image| clock.taken_at(image).is_some_and(|taken| taken >= instant))
+        {
+            clip.start_at(index);
+        } else {
+            clip.trim_before();
+        }
+        clip
+    }
+
+    fn push(&mut self, image: &Arc<ImageData>, clock: &FrameClock) {
+        self.images.push_back(Arc::clone(image));
+        if self.trigger_index.is_some() {
+            if let Some(remaining) = self.remaining.as_mut() {
+                *remaining = remaining.saturating_sub(1);
+            }
+            return;
+        }
+        let instant = self.trigger.time.instant;
+        let reached = clock.taken_at(image).is_none_or(|taken| taken >= instant);
+        if reached || instant.elapsed() > Self::MAX_TRIGGER_DELAY {
+            self.start_at(self.images.len() - 1);
+        } else {
+            self.trim_before();
+        }
+    }
+
+    /// Make `images[index]` the trigger frame, counting it and the frames
+    /// after it as recorded after the trigger.
+    fn start_at(&mut self, index: usize) {
+        let excess = index.saturating_sub(self.n_before);
+        self.images.drain(..excess);
+        let index = index - excess;
+        if let Some(remaining) = self.remaining.as_mut() {
+            *remaining = remaining.saturating_sub(self.images.len() - index);
+        }
+        self.trigger_index = Some(index);
+    }
+
+    /// Keep only the frames that can precede the trigger frame.
+    fn trim_before(&mut self) {
+        while self.images.len() > self.n_before {
+            self.images.pop_front();
+        }
+    }
+
+    /// Render the clip name and hand the frames to the video writer. The
+    /// clip is dropped, and `None` returned, if its name cannot be rendered.
+    fn save(
+        self,
+        sender: &Sender<FramesPacket>,
+        output: &ClipOutput,
+        camera_settings: Option<Arc<AppliedCameraSettings>>,
+    ) -> Option<PathBuf> {
+        let row = self.trigger.row.unwrap_or_default();
+        let context = TriggerContext {
+            row: &row,
+            camera_serial: &output.camera_serial,
+            trigger_time: self.trigger.time.wallclock,
+            source: self.trigger.source,
+            label: self.trigger.label.as_deref(),
+        };
+        let save_path = match output.template.render(&context) {
+            Ok(path) => output.save_folder.join(path),
+            Err(e) => {
+                log::error!("Dropping clip of {} frames: {}", self.images.len(), e);
+                return None;
+            }
+        };
+        let metadata = ClipMetadata {
+            camera: output.camera_serial.clone(),
+            trigger: self.trigger.metadata(),
+            extended_by: self.extended_by.iter().map(Trigger::metadata).collect(),
+            overlap_policy: output.overlap,
+            frames: self.images.len(),
+            format: output.format,
+            encoder: None,
+            fps: None,
+            playback_fps: None,
+            missing_frames: 0,
+            first_frame: self.images.front().map(|image| FrameStamp::from(&**image)),
+            trigger_frame: self
+                .trigger_index
+                .and_then(|index| self.images.get(index))
+                .map(|image| FrameStamp::from(&**image)),
+            last_frame: self.images.back().map(|image| FrameStamp::from(&**image)),
+        };
+        let packet = FramesPacket {
+            images: self.images,
+            save_path: save_path.clone(),
+            camera_settings,
+            metadata: Some(metadata),
+        };
+        if sender.send(packet).is_err() {
+            log::error!("Failed to send frame packet");
+        }
+        Some(save_path)
+    }
+}
+
+fn camera_status(
+    output: &ClipOutput,
+    stats: &FrameStats,
+    buffered: usize,
+    queue: usize,
+    recording: &'static str,
+) -> CameraStatus {
+    CameraStatus {
+        camera: output.camera_serial.clone(),
+        fps: stats.fps(),
+        frames: stats.frames,
+        dropped: stats.dropped,
+        last_nframe: stats.last_nframe,
+        buffered,
+        queue,
+        recording,
+        disk_free_bytes: disk_free(&output.save_folder).ok(),
+    }
 }
 
+/// Buffer frames from one camera and hand clips to the video writer.
+///
+/// Clips are named by rendering `output.template` below the save folder.
+/// A trigger arriving while a clip is still being recorded is handled
+/// according to `output.overlap`. `camera_settings`, if known, is saved
+/// alongside every clip. Control requests are answered here, with the frame
+/// they arrived with being the one saved by `snapshot`. If `events` is
+/// enabled a `CameraStatus` is published every status interval, even while
+/// no frames arrive.
 pub fn frame_handler(
     receiver: Receiver<(Arc<ImageData>, MessageType)>,
-    n_before: usize,
-    n_after: usize,
-    save_folder: String,
+    mut output: ClipOutput,
+    camera_settings: Option<Arc<AppliedCameraSettings>>,
+    events: EventPublisher,
 ) {
     log::info!("Starting frame handler");
 
-    let save_path = Path::new(&save_folder);
-    if !save_path.exists() {
-        create_dir_all(save_path).unwrap();
+    // every clip creates its own directory, and fails on its own if it can't
+    if let Err(e) = create_dir_all(&output.save_folder) {
+        log::error!(
+            "Failed to create save folder {}: {}",
+            output.save_folder.display(),
+            e
+        );
     }
     let (frame_packet_sender, frame_packet_receiver) = unbounded::<FramesPacket>();
-    let frame_handler_thread = thread::spawn(move || {
-        if let Err(e) = video_writer(frame_packet_receiver) {
-            log::error!("Error in video writer: {}", e);
-        }
-    });
+    let writer_output = output.clone();
+    let writer_events = events.clone();
+    let frame_handler_thread =
+        thread::spawn(move || video_writer(frame_packet_receiver, writer_output, writer_events));
 
-    let max_length = n_before + n_after;
-    let mut frame_buffer: VecDeque<Arc<ImageData>> = VecDeque::with_capacity(max_length);
-    let mut switch = false;
-    let mut counter = n_after;
-    let mut trigger_data: KalmanEstimateRow = Default::default();
+    // frames before the next trigger; every clip holds its own references
+    let mut frame_buffer: VecDeque<Arc<ImageData>> =
+        VecDeque::with_capacity(output.n_buffered() + 1);
+    let mut clock = FrameClock::new();
+    let mut clips: Vec<Clip> = Vec::new();
+    let mut manual: Option<Clip> = None;
+    let mut last_accepted: Option<Instant> = None;
+    let mut stats = FrameStats::new();
+    let status_interval = events.status_interval();
+    let mut next_status = Instant::now() + status_interval;
     let mut i_iter = 0;
 
     loop {
@@ -139,35 +728,94 @@ pub fn frame_handler(
             log::debug!("Backpressure on receiver: {:?}", receiver.len());
         }
 
-        let (image_data, incoming) = match receiver.recv() {
+        let recording = match (&manual, clips.is_empty()) {
+            (Some(_), _) => "manual",
+            (None, false) => "trigger",
+            (None, true) => "idle",
+        };
+        if events.is_enabled() && Instant::now() >= next_status {
+            let status = camera_status(
+                &output,
+                &stats,
+                frame_buffer.len(),
+                receiver.len(),
+                recording,
+            );
+            events.publish(Event::Status(status));
+            stats.restart_window();
+            next_status = Instant::now() + status_interval;
+        }
+
+        let received = if events.is_enabled() {
+            receiver.recv_deadline(next_status)
+        } else {
+            receiver.recv().map_err(RecvTimeoutError::from)
+        };
+        let (image_data, incoming) = match received {
             Ok(data) => data,
-            Err(_) => {
+            Err(RecvTimeoutError::Timeout) => continue,
+            Err(RecvTimeoutError::Disconnected) => {
                 log::error!("Failed to receive data");
                 break;
             }
         };
+        clock.record(&image_data, Instant::now());
 
+        let mut command = None;
+        let mut trigger = None;
         match incoming {
-            MessageType::JsonData(kalman_row) => {
-                trigger_data = kalman_row;
-                switch = true;
+            MessageType::JsonData { row, time, .. } => {
                 log::info!("Received Kalman data");
-                log::debug!("{:?}", trigger_data);
+                log::debug!("{:?}", row);
+                trigger = Some(Trigger::tracked(row, time));
             }
-            MessageType::Text(message) => {
-                if message == "kill" {
-                    log::info!("Received kill message");
-                    if frame_packet_sender
-                        .send(FramesPacket {
-                            images: VecDeque::new(),
-                            save_path: PathBuf::from("kill"),
-                        })
-                        .is_err()
-                    {
-                        log::error!("Failed to send kill signal");
-                    }
-                    break;
+            MessageType::Trigger {
+                source,
+                label,
+                time,
+            } => {
+                log::info!(
+                    "Received {} trigger{}",
+                    source,
+                    label
+                        .as_deref()
+                        .map(|label| format!(" {:?}", label))
+                        .unwrap_or_default()
+                );
+                trigger = Some(Trigger::new(source, label, time));
+            }
+            message if message.is_kill() => {
+                log::info!("Received kill message");
+                if let Some(clip) = manual.take() {
+                    log::warn!("Saving manual recording before shutting down");
+                    clip.save(&frame_packet_sender, &output, camera_settings.clone());
                 }
+                for clip in clips.drain(..) {
+                    log::warn!("Saving incomplete clip before shutting down");
+                    clip.save(&frame_packet_sender, &output, camera_settings.clone());
+                }
+                if frame_packet_sender
+                    .send(FramesPacket {
+                        images: VecDeque::new(),
+                        save_path: PathBuf::from("kill"),
+                        camera_settings: None,
+                        metadata: None,
+                    })
+                    .is_err()
+                {
+                    log::error!("Failed to send kill signal");
+                }
+                break;
+            }
+            MessageType::Command(request) => command = Some(request),
+            MessageType::FramerateChanged(fps) => {
+                output.set_fps(fps);
+                log::info!(
+                    "Framerate changed to {} fps, recording {} frames before and {} after triggers",
+                    fps,
+                    output.n_before,
+                    output.n_after
+                );
             }
             MessageType::Empty => {}
             _ => {
@@ -175,36 +823,128 @@ pub fn frame_handler(
             }
         }
 
-        if frame_buffer.len() == max_length {
-            frame_buffer.pop_front();
+        if let Some(trigger) = trigger {
+            let pending = clips.len() + frame_packet_sender.len();
+            match (output.overlap, clips.last_mut()) {
+                (OverlapPolicy::Extend, Some(clip)) => {
+                    log::info!("Extending the current clip for the overlapping trigger");
+                    clip.remaining = Some(output.n_after);
+                    clip.extended_by.push(trigger);
+                }
+                (OverlapPolicy::Refractory, _)
+                    if last_accepted.is_some_and(|time| time.elapsed() < output.refractory) =>
+                {
+                    log::info!(
+                        "Ignoring {} trigger within the refractory period",
+                        trigger.source
+                    );
+                }
+                _ if pending >= output.max_pending_clips => {
+                    log::warn!(
+                        "Dropping {} trigger, {} clips are already pending",
+                        trigger.source,
+                        output.max_pending_clips
+                    );
+                }
+                _ => {
+                    last_accepted = Some(Instant::now());
+                    clips.push(Clip::new(
+                        trigger,
+                        &frame_buffer,
+                        output.n_before,
+                        Some(output.n_after),
+                        &clock,
+                    ));
+                }
+            }
         }
-        frame_buffer.push_back(image_data);
 
-        if switch {
-            counter -= 1;
-
-            if counter == 0 {
-                let time_to_save = Instant::now();
-                log::info!("Writing frames to disk");
+        // frames kept in memory per clip, by length and by size, though
+        // never fewer than the pre-trigger frames and the trigger's own
+        let max_length = (output.n_before + output.n_max)
+            .min(output.max_clip_bytes / image_data.data.len().max(1))
+            .max(output.n_before + 1);
+        stats.record(&image_data);
+        for clip in clips.iter_mut().chain(manual.as_mut()) {
+            clip.push(&image_data, &clock);
+        }
+        frame_buffer.push_back(Arc::clone(&image_data));
+        while frame_buffer.len() > output.n_buffered() {
+            frame_buffer.pop_front();
+        }
 
-                let video_name = PathBuf::from(format!(
-                    "{}/obj_id_{}_frame_{}",
-                    save_folder, trigger_data.obj_id, trigger_data.frame
-                ));
+        if manual
+            .as_ref()
+            .is_some_and(|clip| clip.images.len() >= max_length)
+        {
+            log::warn!("Manual recording reached its maximum length, saving it");
+            let clip = manual.take().expect("checked above");
+            clip.save(&frame_packet_sender, &output, camera_settings.clone());
+        }
 
-                let packet = FramesPacket {
-                    images: frame_buffer.clone(),
-                    save_path: video_name,
-                };
-                if frame_packet_sender.send(packet).is_err() {
-                    log::error!("Failed to send frame packet");
+        if let Some(request) = command {
+            let result = match &request.command {
+                ControlCommand::Status => serde_json::to_value(camera_status(
+                    &output,
+                    &stats,
+                    frame_buffer.len(),
+                    receiver.len(),
+                    recording,
+                ))
+                .map_err(Into::into),
+                ControlCommand::StartRecording => match manual {
+                    Some(_) => Err(anyhow!("A manual recording is already running")),
+                    None => {
+                        log::info!("Starting manual recording");
+                        let trigger = Trigger::new(TriggerSource::Control, None, request.received);
+                        manual = Some(Clip::new(
+                            trigger,
+                            &frame_buffer,
+                            output.n_before,
+                            None,
+                            &clock,
+                        ));
+                        Ok(json!({ "recording": true }))
+                    }
+                },
+                ControlCommand::StopRecording => match manual.take() {
+                    None => Err(anyhow!("No manual recording is running")),
+                    Some(clip) => {
+                        log::info!("Stopping manual recording");
+                        let frames = clip.images.len();
+                        clip.save(&frame_packet_sender, &output, camera_settings.clone())
+                            .context("The clip could not be named")
+                            .map(|path| json!({ "path": path, "frames": frames }))
+                    }
+                },
+                ControlCommand::Snapshot => {
+                    let path = output.save_folder.join("snapshots").join(format!(
+                        "{}_{}.png",
+                        output.camera_serial,
+                        Local::now().format("%Y%m%d_%H%M%S_%3f")
+                    ));
+                    save_snapshot(&image_data, &path).map(|()| {
+                        log::info!("Saved snapshot {}", path.display());
+                        json!({ "path": path, "nframe": image_data.nframe })
+                    })
                 }
+                command => Err(anyhow!("{:?} is not handled by the frame handler", command)),
+            };
+            request.answer(&output.camera_serial, result);
+        }
 
-                log::debug!("Time to save: {:?}", time_to_save.elapsed());
+        // extended clips are cut at the same length as manual recordings
+        let (complete, pending): (Vec<Clip>, Vec<Clip>) = clips
+            .into_iter()
+            .partition(|clip| clip.remaining == Some(0) || clip.images.len() >= max_length);
+        clips = pending;
+        for clip in complete {
+            let time_to_save = Instant::now();
+            log::info!("Writing frames to disk");
 
-                counter = n_after;
-                switch = false;
-            }
+            clip.save(&frame_packet_sender, &output, camera_settings.clone());
+
+            log::debug!("Time to save: {:?}", time_to_save.elapsed());
         }
     }
     frame_handler_thread.join().unwrap();
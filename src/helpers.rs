@@ -1,9 +1,14 @@
 // Standard library imports
+use std::ffi::CString;
+use std::io;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
 use std::time::{SystemTime, UNIX_EPOCH};
 
 // Current crate and supermodule imports
 
-#[allow(dead_code)]
+/// Wall-clock time in seconds since the Unix epoch, the same scale as
+/// `KalmanEstimateRow::timestamp`.
 pub fn time() -> f64 {
     match SystemTime::now().duration_since(UNIX_EPOCH) {
         Ok(duration) => {
@@ -14,3 +19,16 @@ pub fn time() -> f64 {
         Err(_) => panic!("SystemTime before UNIX EPOCH!"),
     }
 }
+
+/// Bytes available to unprivileged users on the filesystem holding `path`.
+pub fn disk_free(path: &Path) -> io::Result<u64> {
+    let path = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    // SAFETY: `path` is NUL-terminated and `stat` is a plain C struct that
+    // statvfs fills in
+    let mut stat: libc::statvfs = unsafe { std::mem::zeroed() };
+    if unsafe { libc::statvfs(path.as_ptr(), &mut stat) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
@@ -0,0 +1,21 @@
+// Local module declarations
+pub mod acquisition;
+pub mod archive;
+pub mod backend;
+pub mod config;
+pub mod control_api;
+pub mod encoders;
+pub mod events;
+pub mod filters;
+pub mod fmf;
+pub mod frames;
+pub mod helpers;
+pub mod messages;
+pub mod settings;
+pub mod simulated;
+pub mod structs;
+pub mod templates;
+pub mod triggers;
+pub mod ximea_camera;
+
+pub use ximea_camera::{CameraConfig, CameraError, DeviceInfo, XimeaCamera};
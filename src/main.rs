@@ -1,26 +1,36 @@
 // External crate imports
+use anyhow::anyhow;
 use clap::Parser;
 use crossbeam::channel;
-use image::{ImageBuffer, Luma};
 
 use std::sync::Arc;
 use std::thread;
+use std::time::Duration;
 
 // Local module declarations
-mod camera;
 mod cli;
-mod frames;
-mod messages;
-mod structs;
 
 // Imports from local modules
-use camera::*;
-use cli::Args;
-use frames::frame_handler;
-use messages::{connect_to_socket, parse_message, subscribe_to_messages};
-use structs::*;
+use cli::{Cli, Command, PrintConfigArgs, RecordArgs};
+use ximea_camera::acquisition::acquisition_loop;
+use ximea_camera::backend::CameraBackend;
+use ximea_camera::config::ClipFormat;
+use ximea_camera::control_api::{bind_control_socket, serve_control_requests};
+use ximea_camera::encoders::{self, Encoder};
+use ximea_camera::events::{bind_event_socket, publish_events, EventPublisher};
+use ximea_camera::filters::TriggerFilter;
+use ximea_camera::frames::{frame_handler, ClipOutput};
+use ximea_camera::messages::{
+    connect_to_socket, handshake, shutdown_pair, subscribe_to_messages, subscribe_topics,
+};
+use ximea_camera::settings::AppliedCameraSettings;
+use ximea_camera::simulated::{SimulatedCamera, SimulationConfig};
+use ximea_camera::structs::*;
+use ximea_camera::triggers::ManualTriggers;
+use ximea_camera::ximea_camera::list_devices;
+use ximea_camera::{CameraConfig, XimeaCamera};
 
-fn main() -> Result<(), i32> {
+fn main() -> anyhow::Result<()> {
     // set logging level
     if std::env::var_os("RUST_LOG").is_none() {
         std::env::set_var("RUST_LOG", "info");
@@ -30,101 +40,334 @@ fn main() -> Result<(), i32> {
     env_logger::init();
 
     // Parse command line arguments
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    log::debug!("Command-line arguments: {:?}", &args);
+    log::debug!("Command-line arguments: {:?}", &cli);
 
-    // Open the camera
-    let mut cam = xiapi::open_device(Some(0))?;
+    match cli.command {
+        Command::Record(args) => record(args),
+        Command::ListCameras => list_cameras(),
+        Command::PrintConfig(args) => print_config(args),
+    }
+}
 
-    // Set camera parameters
-    set_camera_parameters(&mut cam, &args)?;
+fn list_cameras() -> anyhow::Result<()> {
+    let devices = list_devices()?;
+    if devices.is_empty() {
+        println!("No XIMEA cameras found");
+        return Ok(());
+    }
 
-    // calculate frames before and after
-    let n_before = (args.t_before * args.fps) as usize;
-    let n_after = (args.t_after * args.fps) as usize;
-    log::debug!(
-        "Recording {} frames before and {} after trigger",
-        n_before,
-        n_after
-    );
+    println!("Found {} camera(s)", devices.len());
+    for device in devices {
+        println!(
+            "[{}] {} serial {}",
+            device.index, device.model, device.serial
+        );
 
-    // Connect to ZMQ; return error if connection fails
-    log::debug!("Connecting to ZMQ server at {}", args.sub_port);
+        let capabilities = match XimeaCamera::open(Some(&device.serial))
+            .and_then(|mut camera| camera.capabilities())
+        {
+            Ok(capabilities) => capabilities,
+            Err(e) => {
+                println!("    failed to query capabilities: {}", e);
+                continue;
+            }
+        };
+        println!(
+            "    sensor:         {}x{}",
+            capabilities.sensor_width, capabilities.sensor_height
+        );
+        println!(
+            "    image formats:  {}",
+            capabilities.image_formats.join(", ")
+        );
+        println!(
+            "    max framerate:  {:.1} fps at {}x{}",
+            capabilities.max_framerate, capabilities.roi.0, capabilities.roi.1
+        );
+        println!(
+            "    exposure range: {} - {} µs",
+            capabilities.exposure_min_us, capabilities.exposure_max_us
+        );
+    }
+
+    Ok(())
+}
+
+fn print_config(args: PrintConfigArgs) -> anyhow::Result<()> {
+    let config = args.record.resolve()?;
+    print!("{}", args.format.render(&config)?);
+    config.validate().map_err(|e| anyhow!(e))
+}
+
+fn record(args: RecordArgs) -> anyhow::Result<()> {
+    let config = args.resolve()?;
+    config.validate().map_err(|e| anyhow!(e))?;
+    let n_cameras = config.cameras.len();
+    let encoder = match config.output.format {
+        ClipFormat::Video => Some(Encoder::select(&config.output.encoder)?),
+        ClipFormat::Ffv1 => {
+            encoders::init()?;
+            None
+        }
+        ClipFormat::Raw
+        | ClipFormat::Tiff
+        | ClipFormat::Png
+        | ClipFormat::Fmf
+        | ClipFormat::Ufmf => None,
+    };
+
+    // one context for every socket, which inproc:// endpoints need
+    let context = zmq::Context::new();
+
+    if config.messaging.handshake {
+        let hello = serde_json::json!({
+            "type": "hello",
+            "client": env!("CARGO_PKG_NAME"),
+            "cameras": config.cameras.iter().map(|c| &c.serial).collect::<Vec<_>>(),
+        });
+        let reply = handshake(
+            &context,
+            &config.messaging.req_endpoint(),
+            &hello,
+            config.messaging.handshake_timeout_ms,
+        )?;
+        log::info!("Tracker answered handshake: {}", reply);
+    }
 
     // Connect to ZMQ subscriber
-    let subscriber = connect_to_socket(&args.sub_port, zmq::SUB);
+    let subscriber = connect_to_socket(&context, &config.messaging.sub_endpoint(), zmq::SUB)?;
+    subscribe_topics(&subscriber, &config.messaging.topics)?;
+    let control_socket = match &config.messaging.control_endpoint {
+        Some(endpoint) => Some(bind_control_socket(&context, endpoint)?),
+        None => None,
+    };
+
+    // events from every camera go out through one publisher thread, since
+    // ZMQ sockets must not be shared between threads
+    let (events, publisher_thread) = match &config.messaging.event_endpoint {
+        Some(endpoint) => {
+            let socket = bind_event_socket(&context, endpoint)?;
+            let status_interval = Duration::from_secs_f32(config.messaging.status_interval_s);
+            let (events, receiver) = EventPublisher::new(status_interval);
+            let thread = thread::spawn(move || publish_events(socket, receiver));
+            (events, Some(thread))
+        }
+        None => (EventPublisher::default(), None),
+    };
 
-    // Set save folder
-    let save_folder = args.save_folder.clone();
+    // messages for the cameras, from the subscriber, control socket and
+    // manual triggers; anything sent before acquisition starts waits here
+    let (msg_sender, msg_receiver) = channel::unbounded::<MessageType>();
+    let manual_triggers = ManualTriggers::start(&config.manual_triggers, &msg_sender)?;
 
-    // spawn writer thread
-    let (sender, receiver) = channel::unbounded::<(Arc<ImageData>, MessageType)>();
-    let frame_handler_thread =
-        thread::spawn(move || frame_handler(receiver, n_before, n_after, save_folder));
+    // spawn one acquisition thread per camera, each with its own frame handler
+    let (ready_sender, ready_receiver) = channel::unbounded::<anyhow::Result<()>>();
+    let (start_sender, start_receiver) = channel::bounded::<bool>(n_cameras);
+    let mut message_senders = Vec::with_capacity(n_cameras);
+    let mut threads = Vec::with_capacity(n_cameras);
+    let mut camera_serials = Vec::with_capacity(n_cameras);
 
-    // spawn subscriber thread
-    let (msg_sender, msg_receiver) = channel::unbounded::<String>();
-    let subscriber_thread = thread::spawn(move || subscribe_to_messages(subscriber, msg_sender));
-
-    // create image buffer
-    let buffer = cam.start_acquisition()?;
-
-    // start acquisition
-    log::info!("Starting acquisition");
-    loop {
-        // Check if there's a message from the subscriber thread
-        let msg = match msg_receiver.try_recv() {
-            Ok(message) => Some(message),
-            Err(_) => None,
+    // validate() guarantees a save folder
+    let save_folder = config.output.save_folder.clone().unwrap_or_default();
+    // with several cameras every clip goes into a shared event directory,
+    // unless the template already tells the cameras apart
+    let template = if n_cameras > 1 && !config.output.template.uses("camera_serial") {
+        format!("{}/{{camera_serial}}", config.output.template)
+            .parse()
+            .map_err(|e: String| anyhow!(e))?
+    } else {
+        config.output.template.clone()
+    };
+
+    for (i, camera_config) in config.cameras.into_iter().enumerate() {
+        let camera_serial = camera_config
+            .serial
+            .clone()
+            .unwrap_or_else(|| format!("cam{}", i));
+
+        // calculate frames before and after
+        let (n_before, n_after) = config.recording.frames(camera_config.fps);
+        log::debug!(
+            "Recording {} frames before and {} after trigger",
+            n_before,
+            n_after
+        );
+        let output = ClipOutput {
+            n_before,
+            n_after,
+            n_max: config.recording.max_frames(camera_config.fps),
+            recording: config.recording.clone(),
+            max_clip_bytes: config.recording.max_clip_bytes(),
+            overlap: config.recording.overlap,
+            refractory: Duration::from_secs_f32(config.recording.refractory_s),
+            max_pending_clips: config.recording.max_pending_clips,
+            save_folder: save_folder.clone(),
+            template: template.clone(),
+            camera_serial: camera_serial.clone(),
+            encoder: encoder.clone(),
+            format: config.output.format,
+            ufmf: config.output.ufmf.clone(),
+            fps: camera_config.fps,
+            playback_fps: config.output.playback_fps,
         };
+        camera_serials.push(camera_serial);
 
-        // parse message
-        let mut parsed_message = MessageType::Empty;
-        if let Some(message) = msg {
-            parsed_message = parse_message(&message);
-            log::debug!("Parsed message: {:?}", parsed_message);
-
-            // check if got "kill" in parsed_message
-            if let MessageType::Text(data) = &parsed_message {
-                if data == "kill" {
-                    break;
-                }
-            }
-        } else {
-            log::debug!("No valid message received.");
+        let (message_sender, message_receiver) = channel::unbounded::<MessageType>();
+        message_senders.push(message_sender);
+
+        let sim = config.simulation.clone();
+        let events = events.clone();
+        let ready_sender = ready_sender.clone();
+        let start_receiver = start_receiver.clone();
+        threads.push(thread::spawn(move || {
+            camera_thread(
+                camera_config,
+                sim,
+                output,
+                events,
+                ready_sender,
+                start_receiver,
+                message_receiver,
+            )
+        }));
+    }
+
+    // only start acquiring once every camera opened successfully
+    let mut startup_error = None;
+    for _ in 0..n_cameras {
+        if let Err(e) = ready_receiver.recv()? {
+            log::error!("Failed to initialize camera: {:#}", e);
+            startup_error.get_or_insert(e);
+        }
+    }
+    for _ in 0..n_cameras {
+        start_sender.send(startup_error.is_none())?;
+    }
+    if let Some(e) = startup_error {
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        manual_triggers.stop();
+        drop(events);
+        if let Some(thread) = publisher_thread {
+            thread.join().unwrap();
         }
+        return Err(e);
+    }
 
-        // Get frame from camera
-        let frame = buffer.next_image::<u8>(None)?;
-
-        // Put frame data to struct
-        let image_data = Arc::new(ImageData {
-            width: frame.width(),
-            height: frame.height(),
-            nframe: frame.nframe(),
-            acq_nframe: frame.acq_nframe(),
-            timestamp_raw: frame.timestamp_raw(),
-            exposure_time: frame.exposure_time_us(),
-            data: ImageBuffer::<Luma<u8>, Vec<u8>>::from(frame),
-        });
+    // spawn subscriber thread
+    let topics = config.messaging.topics.clone();
+    let filter = TriggerFilter::new(config.filter.clone());
+    let (shutdown, shutdown_receiver) = shutdown_pair(&context)?;
+    let control_sender = msg_sender.clone();
+    let subscriber_thread = thread::spawn(move || {
+        subscribe_to_messages(subscriber, shutdown_receiver, topics, filter, msg_sender)
+    });
+
+    // control requests join the trigger messages on their way to the cameras
+    let control = match control_socket {
+        Some(socket) => {
+            let (control_shutdown, shutdown_receiver) = shutdown_pair(&context)?;
+            let control_thread = thread::spawn(move || {
+                serve_control_requests(socket, shutdown_receiver, camera_serials, control_sender)
+            });
+            Some((control_shutdown, control_thread))
+        }
+        None => None,
+    };
 
-        // send frame with the incoming parsed message
-        match sender.send((image_data, parsed_message)) {
-            Ok(_) => {
-                log::trace!("Sent frame to frame handler");
+    // forward every message to all cameras so they record the same events
+    while let Ok(message) = msg_receiver.recv() {
+        log::debug!("Parsed message: {:?}", message);
+
+        let is_kill = message.is_kill();
+        for sender in &message_senders {
+            if sender.send(message.clone()).is_err() {
+                log::warn!("Failed to forward message to camera thread");
             }
-            Err(_e) => {
-                log::warn!("Failed to send frame to frame handler");
-            } //log::error!("Failed to send frame: {}", e),
         }
+        if is_kill {
+            break;
+        }
+    }
+
+    // the subscriber stops by itself on "kill", this covers every other exit
+    if shutdown.stop().is_err() {
+        log::debug!("Subscriber thread already stopped");
+    }
+    if let Some((control_shutdown, control_thread)) = control {
+        if control_shutdown.stop().is_err() {
+            log::debug!("Control thread already stopped");
+        }
+        control_thread.join().unwrap();
     }
+    manual_triggers.stop();
+
+    // stop camera threads, which in turn stop their frame handlers
+    for thread in threads {
+        thread.join().unwrap();
+    }
+    subscriber_thread.join().unwrap();
+
+    // the publisher drains its queue and stops once every handle is gone
+    drop(events);
+    if let Some(thread) = publisher_thread {
+        thread.join().unwrap();
+    }
+
+    Ok(())
+}
 
-    // stop acquisition
-    buffer.stop_acquisition()?;
+/// Open one camera, wait for the go-ahead and run its acquisition loop until
+/// a "kill" message arrives.
+fn camera_thread(
+    config: CameraConfig,
+    sim: Option<SimulationConfig>,
+    output: ClipOutput,
+    events: EventPublisher,
+    ready: channel::Sender<anyhow::Result<()>>,
+    start: channel::Receiver<bool>,
+    messages: channel::Receiver<MessageType>,
+) {
+    // Open and configure the camera, then check what it actually applied
+    let camera: anyhow::Result<Box<dyn CameraBackend>> = match sim {
+        Some(sim) => SimulatedCamera::new(&config, sim).map(|c| Box::new(c) as _),
+        None => XimeaCamera::new(&config)
+            .map(|c| Box::new(c) as _)
+            .map_err(Into::into),
+    };
+    let camera = camera.and_then(|mut camera| {
+        let settings = verify_settings(camera.as_mut(), &config)?;
+        Ok((camera, settings))
+    });
+
+    let (mut camera, settings) = match camera {
+        Ok((camera, settings)) => {
+            let _ = ready.send(Ok(()));
+            (camera, settings)
+        }
+        Err(e) => {
+            let _ = ready.send(Err(e));
+            return;
+        }
+    };
+
+    let camera_serial = output.camera_serial.clone();
+    let (frames, frame_receiver) = channel::unbounded::<(Arc<ImageData>, MessageType)>();
+    let frame_handler_thread = thread::spawn(move || {
+        frame_handler(frame_receiver, output, Some(Arc::new(settings)), events)
+    });
+
+    if let Ok(true) = start.recv() {
+        if let Err(e) = acquisition_loop(camera.as_mut(), &camera_serial, &messages, &frames) {
+            log::error!("Acquisition failed: {:#}", e);
+        }
+    }
 
     // send kill signal to writer thread
-    match sender.send((
+    match frames.send((
         Arc::new(ImageData::default()),
         MessageType::Text("kill".to_string()),
     )) {
@@ -135,10 +378,30 @@ fn main() -> Result<(), i32> {
             log::error!("Failed to send kill trigger to frame handler.")
         }
     }
-
-    // stop frame handler
     frame_handler_thread.join().unwrap();
-    subscriber_thread.join().unwrap();
+}
 
-    Ok(())
+/// Log the settings the camera ended up with and, if a tolerance is
+/// configured, fail when they stray too far from the request.
+fn verify_settings(
+    camera: &mut dyn CameraBackend,
+    config: &CameraConfig,
+) -> anyhow::Result<AppliedCameraSettings> {
+    let settings = camera.applied_settings()?;
+    log::info!(
+        "Applied camera settings: {}",
+        serde_json::to_string(&settings)?
+    );
+
+    if let Some(tolerance) = config.settings_tolerance {
+        let mismatches = settings.mismatches(config, tolerance);
+        if !mismatches.is_empty() {
+            return Err(anyhow!(
+                "Camera settings differ from the request: {}",
+                mismatches.join("; ")
+            ));
+        }
+    }
+
+    Ok(settings)
 }
@@ -1,30 +1,115 @@
-use super::structs::{KalmanEstimateRow, MessageType};
+use super::config::{TopicAction, TopicConfig};
+use super::filters::TriggerFilter;
+use super::helpers::time;
+use super::structs::{KalmanEstimateRow, MessageType, TriggerSource, TriggerTime};
+use anyhow::{Context, Result};
 use crossbeam::channel;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
-pub fn connect_to_socket(port: &str, socket_type: zmq::SocketType) -> zmq::Socket {
-    let context = zmq::Context::new();
-    let socket = context.socket(socket_type).unwrap();
-    println!("Connecting to port {}", port);
+/// Connect a socket of `socket_type` to `endpoint`.
+///
+/// All sockets of one process should share `context`, which `inproc://`
+/// endpoints require.
+pub fn connect_to_socket(
+    context: &zmq::Context,
+    endpoint: &str,
+    socket_type: zmq::SocketType,
+) -> Result<zmq::Socket> {
+    let socket = context
+        .socket(socket_type)
+        .context("Failed to create ZMQ socket")?;
+    log::info!("Connecting to {}", endpoint);
     socket
-        .connect(format!("tcp://127.0.0.1:{}", port).as_str())
-        .unwrap();
-    if socket_type == zmq::SUB {
-        socket.set_subscribe(b"trigger").unwrap();
-    };
+        .connect(endpoint)
+        .with_context(|| format!("Failed to connect to {}", endpoint))?;
+    Ok(socket)
+}
+
+/// Subscribe a SUB socket to every topic that is not ignored.
+///
+/// ZeroMQ filters by prefix, so an ignored topic only needs an entry when it
+/// shares a prefix with a subscribed one.
+pub fn subscribe_topics(socket: &zmq::Socket, topics: &[TopicConfig]) -> Result<()> {
+    for topic in topics {
+        if topic.action != TopicAction::Ignore {
+            socket
+                .set_subscribe(topic.topic.as_bytes())
+                .with_context(|| format!("Failed to subscribe to topic {:?}", topic.topic))?;
+            log::info!("Subscribed to {:?} ({:?})", topic.topic, topic.action);
+        }
+    }
+    Ok(())
+}
+
+/// Send `hello` on a REQ socket and wait up to `timeout_ms` for the reply,
+/// which is returned as text.
+pub fn handshake(
+    context: &zmq::Context,
+    endpoint: &str,
+    hello: &serde_json::Value,
+    timeout_ms: u64,
+) -> Result<String> {
+    let socket = connect_to_socket(context, endpoint, zmq::REQ)?;
+    let timeout = i32::try_from(timeout_ms).unwrap_or(i32::MAX);
+    socket.set_sndtimeo(timeout)?;
+    socket.set_rcvtimeo(timeout)?;
+    // don't hang on exit if the tracker never picked up the request
+    socket.set_linger(0)?;
+
     socket
+        .send(hello.to_string().as_str(), 0)
+        .with_context(|| format!("Failed to send handshake to {}", endpoint))?;
+    let reply = socket
+        .recv_string(0)
+        .with_context(|| {
+            format!(
+                "No handshake reply from {} within {} ms",
+                endpoint, timeout_ms
+            )
+        })?
+        .unwrap_or_else(|bytes| String::from_utf8_lossy(&bytes).into_owned());
+    Ok(reply)
 }
 
-pub fn parse_message(message: &str) -> MessageType {
+/// Turn the payload of a trigger topic into a message.
+///
+/// Besides `KalmanEstimateRow`s this accepts `{"trigger": "manual",
+/// "label": ...}`, which records without tracking data.
+pub fn parse_message(topic: &str, message: &str) -> MessageType {
     if message.trim().is_empty() {
         return MessageType::Empty;
     }
 
+    // every field of KalmanEstimateRow is optional, so look for this first
+    if let Ok(serde_json::Value::Object(fields)) = serde_json::from_str(message) {
+        if let Some(trigger) = fields.get("trigger") {
+            if trigger != "manual" {
+                return MessageType::InvalidJson(
+                    message.to_string(),
+                    format!("Unknown trigger {}, expected \"manual\"", trigger),
+                );
+            }
+            return MessageType::Trigger {
+                source: TriggerSource::Message,
+                label: fields
+                    .get("label")
+                    .and_then(|label| label.as_str())
+                    .map(str::to_string),
+                time: TriggerTime::now(),
+            };
+        }
+    }
+
     match serde_json::from_str::<KalmanEstimateRow>(message) {
-        Ok(data) => MessageType::JsonData(data),
+        Ok(row) => MessageType::JsonData {
+            topic: topic.to_string(),
+            row,
+            time: TriggerTime::now(),
+        },
         Err(e) => {
             if e.is_data() {
                 // If the error is due to data format issues, return InvalidJson
-                MessageType::InvalidJson(message.to_string(), e)
+                MessageType::InvalidJson(message.to_string(), e.to_string())
             } else {
                 // For other types of errors, treat it as a plain text message
                 MessageType::Text(message.to_string())
@@ -33,45 +118,226 @@ pub fn parse_message(message: &str) -> MessageType {
     }
 }
 
-pub fn subscribe_to_messages(subscriber: zmq::Socket, msg_sender: channel::Sender<String>) {
+/// Parse `name value`, `name=value` or `{"name": ..., "value": ...}`.
+fn parse_parameter_update(topic: &str, message: &str) -> Option<MessageType> {
+    let (name, value) = match serde_json::from_str::<serde_json::Value>(message) {
+        Ok(serde_json::Value::Object(fields)) => {
+            let name = fields.get("name")?.as_str()?.to_string();
+            let value = match fields.get("value")? {
+                serde_json::Value::String(value) => value.clone(),
+                value => value.to_string(),
+            };
+            (name, value)
+        }
+        _ => {
+            let (name, value) = message.trim().split_once([' ', '='])?;
+            (name.trim().to_string(), value.trim().to_string())
+        }
+    };
+    Some(MessageType::ParameterUpdate {
+        topic: topic.to_string(),
+        name,
+        value,
+    })
+}
+
+/// Interpret a message according to the action configured for its topic.
+///
+/// Returns `None` for ignored and unknown topics.
+pub fn route_message(topics: &[TopicConfig], topic: &str, message: &str) -> Option<MessageType> {
+    let Some(config) = topics.iter().find(|t| t.topic == topic) else {
+        log::warn!("Received message on unconfigured topic {:?}", topic);
+        return None;
+    };
+    match config.action {
+        TopicAction::Trigger => Some(parse_message(topic, message)),
+        TopicAction::Control => Some(MessageType::Control {
+            topic: topic.to_string(),
+            command: message.trim().to_string(),
+        }),
+        TopicAction::ParameterUpdate => {
+            let update = parse_parameter_update(topic, message);
+            if update.is_none() {
+                log::warn!("Invalid parameter update on {:?}: {:?}", topic, message);
+            }
+            update
+        }
+        TopicAction::Ignore => {
+            log::trace!("Ignoring message on {:?}", topic);
+            None
+        }
+    }
+}
+
+/// Split a message into topic and payload. Accepts both a single frame
+/// `"<topic> <payload>"` and a multipart `[topic, payload]` message.
+fn split_topic(parts: Vec<Vec<u8>>) -> (String, String) {
+    let mut parts = parts
+        .into_iter()
+        .map(|part| String::from_utf8_lossy(&part).into_owned());
+    let first = parts.next().unwrap_or_default();
+    let rest: Vec<String> = parts.collect();
+    if !rest.is_empty() {
+        return (first, rest.join(" "));
+    }
+    match first.split_once(' ') {
+        Some((topic, message)) => (topic.to_string(), message.to_string()),
+        None => (first, String::new()),
+    }
+}
+
+/// Tells a running `subscribe_to_messages` to return.
+pub struct SubscriberShutdown {
+    socket: zmq::Socket,
+}
+
+impl SubscriberShutdown {
+    /// Fails if the subscriber has already returned.
+    pub fn stop(&self) -> Result<()> {
+        self.socket
+            .send("stop", zmq::DONTWAIT)
+            .context("Failed to signal subscriber shutdown")
+    }
+}
+
+/// Create the inproc pair used to stop the subscriber: the handle for the
+/// caller and the socket to pass to `subscribe_to_messages`.
+pub fn shutdown_pair(context: &zmq::Context) -> Result<(SubscriberShutdown, zmq::Socket)> {
+    static NEXT_ID: AtomicUsize = AtomicUsize::new(0);
+    let endpoint = format!(
+        "inproc://subscriber-shutdown-{}",
+        NEXT_ID.fetch_add(1, Ordering::Relaxed)
+    );
+
+    let receiver = context.socket(zmq::PAIR)?;
+    receiver.bind(&endpoint)?;
+    let sender = context.socket(zmq::PAIR)?;
+    sender.connect(&endpoint)?;
+    Ok((SubscriberShutdown { socket: sender }, receiver))
+}
+
+/// Running statistics of the delay between the tracker stamping a trigger
+/// (`KalmanEstimateRow::timestamp`) and this process receiving it.
+///
+/// Only meaningful when both hosts have synchronized clocks.
+#[derive(Default)]
+struct LatencyStats {
+    count: u64,
+    sum_ms: f64,
+    min_ms: f64,
+    max_ms: f64,
+}
+
+impl LatencyStats {
+    const REPORT_EVERY: u64 = 100;
+
+    fn record(&mut self, row: &KalmanEstimateRow) {
+        // trackers that don't stamp their messages leave the field at 0
+        if row.timestamp <= 0.0 {
+            return;
+        }
+        let latency_ms = (time() - row.timestamp) * 1000.0;
+        log::debug!(
+            "Trigger for obj_id {} received {:.3} ms after it was sent",
+            row.obj_id,
+            latency_ms
+        );
+
+        if self.count == 0 {
+            self.min_ms = latency_ms;
+            self.max_ms = latency_ms;
+        }
+        self.count += 1;
+        self.sum_ms += latency_ms;
+        self.min_ms = self.min_ms.min(latency_ms);
+        self.max_ms = self.max_ms.max(latency_ms);
+
+        if self.count.is_multiple_of(Self::REPORT_EVERY) {
+            self.report();
+        }
+    }
+
+    fn report(&self) {
+        if self.count > 0 {
+            log::info!(
+                "Trigger latency over {} triggers: mean {:.3} ms, min {:.3} ms, max {:.3} ms",
+                self.count,
+                self.sum_ms / self.count as f64,
+                self.min_ms,
+                self.max_ms
+            );
+        }
+    }
+}
+
+/// Forward routed messages from `subscriber` until a "kill" arrives or
+/// `shutdown` is signalled, blocking in `zmq::poll` in between. Tracker
+/// triggers rejected by `filter` are logged and dropped. If polling fails,
+/// `MessageType::Kill` is sent so the cameras don't wait for triggers that
+/// can no longer arrive.
+pub fn subscribe_to_messages(
+    subscriber: zmq::Socket,
+    shutdown: zmq::Socket,
+    topics: Vec<TopicConfig>,
+    mut filter: TriggerFilter,
+    msg_sender: channel::Sender<MessageType>,
+) {
+    let mut latency = LatencyStats::default();
+
     loop {
-        let msg = match subscriber.recv_string(zmq::DONTWAIT) {
-            Ok(result) => match result {
-                Ok(full_message) => {
-                    let parts: Vec<&str> = full_message.splitn(2, ' ').collect();
-                    if parts.len() == 2 {
-                        let topic = parts[0];
-                        let message = parts[1];
-                        log::debug!("Received message: {:?} {:?}", topic, message);
-                        Some(message.to_string())
-                    } else {
-                        log::warn!("Received message with no topic: {:?}", full_message);
-                        Some(full_message)
-                    }
+        let mut items = [
+            subscriber.as_poll_item(zmq::POLLIN),
+            shutdown.as_poll_item(zmq::POLLIN),
+        ];
+        match zmq::poll(&mut items, -1) {
+            Ok(_) => {}
+            // interrupted by a signal, e.g. SIGUSR1 for a manual trigger
+            Err(zmq::Error::EINTR) => continue,
+            Err(e) => {
+                log::error!("Failed to poll subscriber socket, shutting down: {:?}", e);
+                if msg_sender.send(MessageType::Kill).is_err() {
+                    log::debug!("Main thread already stopped");
                 }
-                Err(e) => {
-                    log::trace!("Failed to parse message: {:?}", e);
-                    None
+                break;
+            }
+        }
+        if items[1].is_readable() {
+            log::info!("Shutdown requested, stopping subscriber thread.");
+            break;
+        }
+        if !items[0].is_readable() {
+            continue;
+        }
+
+        // drain everything that arrived, the poller only wakes us once
+        while let Ok(parts) = subscriber.recv_multipart(zmq::DONTWAIT) {
+            let (topic, message) = split_topic(parts);
+            log::debug!("Received message: {:?} {:?}", topic, message);
+            let Some(message) = route_message(&topics, &topic, &message) else {
+                continue;
+            };
+            if let MessageType::JsonData { row, .. } = &message {
+                latency.record(row);
+                if let Err(reason) = filter.check(row) {
+                    log::info!("Rejected trigger for obj_id {}: {}", row.obj_id, reason);
+                    continue;
                 }
-            },
-            Err(e) => {
-                log::trace!("Failed to receive message: {:?}", e);
-                None
             }
-        };
 
-        if let Some(message) = msg {
-            if let Err(e) = msg_sender.send(message.clone()) {
+            let is_kill = message.is_kill();
+            if let Err(e) = msg_sender.send(message) {
                 log::error!("Failed to send message to main thread: {:?}", e);
-                break;
+                latency.report();
+                return;
             }
 
-            if message == "kill" {
+            if is_kill {
                 log::info!("Kill message received, stopping subscriber thread.");
-                break;
+                latency.report();
+                return;
             }
         }
-
-        std::thread::sleep(std::time::Duration::from_millis(1)); // Adjusted to check every 1ms
     }
+
+    latency.report();
 }
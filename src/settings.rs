@@ -0,0 +1,73 @@
+// External crate imports
+use serde::{Deserialize, Serialize};
+
+// Current crate and supermodule imports
+use crate::structs::PixelFormat;
+use crate::ximea_camera::{CameraConfig, ExposureMode, TriggerMode};
+
+/// What the camera actually ended up using after configuration, read back
+/// from the device rather than copied from the request.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct AppliedCameraSettings {
+    pub serial: Option<String>,
+    pub model: Option<String>,
+    pub width: u32,
+    pub height: u32,
+    pub offset_x: u32,
+    pub offset_y: u32,
+    pub pixel_format: PixelFormat,
+    pub framerate: f32,
+    pub exposure_us: f32,
+    pub gain_db: f32,
+    pub bandwidth_limit_mbps: i32,
+    pub acq_buffer_size: i32,
+    pub buffers_queue_size: i32,
+    pub sensor_temperature_c: Option<f32>,
+}
+
+impl AppliedCameraSettings {
+    /// Describe every setting that differs from `config` by more than the
+    /// relative `tolerance`. ROI and pixel format have to match exactly.
+    pub fn mismatches(&self, config: &CameraConfig, tolerance: f32) -> Vec<String> {
+        let mut mismatches = Vec::new();
+        let differs =
+            |requested: f32, actual: f32| (actual - requested).abs() > tolerance * requested.abs();
+
+        if (self.width, self.height) != (config.width, config.height) {
+            mismatches.push(format!(
+                "ROI size {}x{} instead of {}x{}",
+                self.width, self.height, config.width, config.height
+            ));
+        }
+        if !config.center_roi
+            && (self.offset_x, self.offset_y) != (config.offset_x, config.offset_y)
+        {
+            mismatches.push(format!(
+                "ROI offset ({}, {}) instead of ({}, {})",
+                self.offset_x, self.offset_y, config.offset_x, config.offset_y
+            ));
+        }
+        if self.pixel_format != config.pixel_format {
+            mismatches.push(format!(
+                "pixel format {:?} instead of {:?}",
+                self.pixel_format, config.pixel_format
+            ));
+        }
+        if config.trigger.mode == TriggerMode::FreeRun && differs(config.fps, self.framerate) {
+            mismatches.push(format!(
+                "framerate {} fps instead of {} fps",
+                self.framerate, config.fps
+            ));
+        }
+        // the exposure is shortened to the frame period before it is set
+        let exposure = config.clamp_exposure(config.exposure);
+        if config.exposure_mode == ExposureMode::Manual && differs(exposure, self.exposure_us) {
+            mismatches.push(format!(
+                "exposure {} µs instead of {} µs",
+                self.exposure_us, exposure
+            ));
+        }
+
+        mismatches
+    }
+}
@@ -0,0 +1,292 @@
+// External crate imports
+use anyhow::{bail, Result};
+use clap::ValueEnum;
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::{Deserialize, Serialize};
+
+// Standard library imports
+use std::thread;
+use std::time::{Duration, Instant};
+
+// Current crate and supermodule imports
+use crate::{
+    backend::CameraBackend,
+    settings::AppliedCameraSettings,
+    structs::{ImageData, PixelFormat},
+    ximea_camera::{CameraConfig, ExposureMode, TriggerMode},
+};
+
+/// Longest time between two simulated frames, so a tiny frame rate does
+/// not stall the acquisition thread
+const MAX_FRAME_PERIOD: Duration = Duration::from_secs(60);
+
+/// Time between two frames at `fps`.
+fn frame_period(fps: f32) -> Result<Duration> {
+    if !fps.is_finite() || fps <= 0.0 {
+        bail!("Frame rate must be a finite number greater than 0");
+    }
+    match Duration::try_from_secs_f32(1.0 / fps) {
+        Ok(period) if period <= MAX_FRAME_PERIOD => Ok(period),
+        _ => bail!(
+            "Frame rate must give at most {} s between frames",
+            MAX_FRAME_PERIOD.as_secs()
+        ),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum FramePattern {
+    /// Diagonal gradient that scrolls by one gray level per frame
+    Gradient,
+    /// Bright vertical bar sweeping across the frame
+    MovingBar,
+    /// 32 px checkerboard that inverts every frame
+    Checkerboard,
+    /// Uniform mid-gray, useful to look at the noise alone
+    Flat,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct SimulationConfig {
+    pub pattern: FramePattern,
+    /// Amplitude of the uniform noise added to every pixel, in gray levels
+    pub noise: f32,
+    /// Probability in [0, 1) that a frame is dropped before delivery
+    pub drop_rate: f64,
+}
+
+impl Default for SimulationConfig {
+    fn default() -> Self {
+        Self {
+            pattern: FramePattern::MovingBar,
+            noise: 0.0,
+            drop_rate: 0.0,
+        }
+    }
+}
+
+/// Synthetic camera that produces frames at the configured rate.
+///
+/// Dropped frames behave like on the real device: `nframe` keeps counting
+/// on the "sensor", so the gap is visible to downstream code, while
+/// `acq_nframe` only counts frames that were actually delivered.
+pub struct SimulatedCamera {
+    sim: SimulationConfig,
+    serial: Option<String>,
+    width: u32,
+    height: u32,
+    offset: (u32, u32),
+    pixel_format: PixelFormat,
+    frame_period: Duration,
+    exposure: u32,
+    rng: StdRng,
+    nframe: u32,
+    acq_nframe: u32,
+    acquisition_start: Option<Instant>,
+    next_deadline: Instant,
+}
+
+impl SimulatedCamera {
+    pub fn new(config: &CameraConfig, sim: SimulationConfig) -> Result<Self> {
+        log::info!("Initializing simulated camera ({:?})", sim.pattern);
+        let mut camera = Self {
+            sim,
+            serial: None,
+            width: 0,
+            height: 0,
+            offset: (0, 0),
+            pixel_format: PixelFormat::Mono8,
+            frame_period: Duration::ZERO,
+            exposure: 0,
+            rng: StdRng::from_entropy(),
+            nframe: 0,
+            acq_nframe: 0,
+            acquisition_start: None,
+            next_deadline: Instant::now(),
+        };
+        camera.configure(config)?;
+        Ok(camera)
+    }
+
+    fn render(&mut self) -> Vec<u8> {
+        let (width, height) = (self.width, self.height);
+        let n = self.nframe;
+        let pattern = self.sim.pattern;
+        let noise = self.sim.noise;
+        let pixel_format = self.pixel_format;
+        let max_value = ((1u32 << pixel_format.bits_per_pixel().min(16)) - 1) as f32;
+
+        let mut data =
+            Vec::with_capacity((width * height) as usize * pixel_format.bytes_per_pixel());
+        for y in 0..height {
+            for x in 0..width {
+                let base = match pattern {
+                    FramePattern::Gradient => (x.wrapping_add(y).wrapping_add(n) % 256) as f32,
+                    FramePattern::MovingBar => {
+                        let bar_width = (width / 16).max(1);
+                        let bar_start = n.wrapping_mul(4) % width;
+                        if x.wrapping_sub(bar_start) % width < bar_width {
+                            230.0
+                        } else {
+                            25.0
+                        }
+                    }
+                    FramePattern::Checkerboard => {
+                        if ((x / 32) + (y / 32) + n) % 2 == 1 {
+                            55.0
+                        } else {
+                            200.0
+                        }
+                    }
+                    FramePattern::Flat => 128.0,
+                };
+                let value = if noise > 0.0 {
+                    base + self.rng.gen_range(-noise..=noise)
+                } else {
+                    base
+                };
+                let value = value.clamp(0.0, 255.0);
+
+                match pixel_format.bytes_per_pixel() {
+                    2 => {
+                        let scaled = (value / 255.0 * max_value) as u16;
+                        data.extend_from_slice(&scaled.to_le_bytes());
+                    }
+                    3 => data.extend_from_slice(&[value as u8; 3]),
+                    _ => data.push(value as u8),
+                }
+            }
+        }
+        data
+    }
+}
+
+impl CameraBackend for SimulatedCamera {
+    fn configure(&mut self, config: &CameraConfig) -> Result<()> {
+        if !(0.0..1.0).contains(&self.sim.drop_rate) {
+            bail!("Drop rate must be in the range [0, 1)");
+        }
+        if config.trigger.mode != TriggerMode::FreeRun || !config.strobes.is_empty() {
+            log::warn!("Simulated camera ignores trigger and strobe settings, free-running");
+        }
+        self.serial = config.serial.clone();
+        self.width = config.width;
+        self.height = config.height;
+        self.offset = if config.center_roi {
+            (0, 0)
+        } else {
+            (config.offset_x, config.offset_y)
+        };
+        self.pixel_format = config.pixel_format;
+        self.frame_period = frame_period(config.fps)?;
+        self.exposure = match &config.exposure_mode {
+            ExposureMode::Manual => config.clamp_exposure(config.exposure) as u32,
+            ExposureMode::Auto(auto) => auto.max_exposure_us as u32,
+        };
+        log::debug!(
+            "Simulated camera configured: {}x{} @ {} fps",
+            self.width,
+            self.height,
+            config.fps
+        );
+        Ok(())
+    }
+
+    fn start_acquisition(&mut self) -> Result<()> {
+        let now = Instant::now();
+        self.acquisition_start = Some(now);
+        self.next_deadline = now;
+        self.nframe = 0;
+        self.acq_nframe = 0;
+        Ok(())
+    }
+
+    fn stop_acquisition(&mut self) -> Result<()> {
+        self.acquisition_start = None;
+        Ok(())
+    }
+
+    fn set_parameter(&mut self, name: &str, value: &str) -> Result<()> {
+        let Ok(value) = value.parse::<f32>() else {
+            bail!("Invalid value {:?} for {}", value, name);
+        };
+        if !value.is_finite() || value <= 0.0 {
+            bail!("{} must be a finite number greater than 0", name);
+        }
+        match name {
+            "exposure" => self.exposure = value as u32,
+            "framerate" | "fps" => self.frame_period = frame_period(value)?,
+            _ => bail!(
+                "Parameter {:?} is not supported by the simulated camera",
+                name
+            ),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&mut self, name: &str) -> Result<f32> {
+        match name {
+            "exposure" => Ok(self.exposure as f32),
+            "framerate" | "fps" => Ok(1.0 / self.frame_period.as_secs_f32()),
+            _ => bail!(
+                "Parameter {:?} is not supported by the simulated camera",
+                name
+            ),
+        }
+    }
+
+    fn applied_settings(&mut self) -> Result<AppliedCameraSettings> {
+        Ok(AppliedCameraSettings {
+            serial: self.serial.clone(),
+            model: Some("simulated".to_string()),
+            width: self.width,
+            height: self.height,
+            offset_x: self.offset.0,
+            offset_y: self.offset.1,
+            pixel_format: self.pixel_format,
+            framerate: 1.0 / self.frame_period.as_secs_f32(),
+            exposure_us: self.exposure as f32,
+            gain_db: 0.0,
+            ..Default::default()
+        })
+    }
+
+    fn next_frame(&mut self) -> Result<ImageData> {
+        let Some(start) = self.acquisition_start else {
+            bail!("Acquisition has not been started");
+        };
+
+        // frames lost in "transport" still consume their slot on the timeline
+        while self.sim.drop_rate > 0.0 && self.rng.gen_bool(self.sim.drop_rate) {
+            log::trace!("Simulated drop of frame {}", self.nframe);
+            self.nframe = self.nframe.wrapping_add(1);
+            self.next_deadline += self.frame_period;
+        }
+
+        let now = Instant::now();
+        if self.next_deadline > now {
+            thread::sleep(self.next_deadline - now);
+        }
+        let timestamp_raw = (self.next_deadline - start).as_nanos() as u64;
+        self.next_deadline += self.frame_period;
+
+        let data = self.render();
+        let image_data = ImageData {
+            pixel_format: self.pixel_format,
+            width: self.width,
+            height: self.height,
+            nframe: self.nframe,
+            acq_nframe: self.acq_nframe,
+            timestamp_raw,
+            exposure_time: self.exposure,
+            data,
+        };
+
+        self.nframe = self.nframe.wrapping_add(1);
+        self.acq_nframe = self.acq_nframe.wrapping_add(1);
+
+        Ok(image_data)
+    }
+}
@@ -1,16 +1,91 @@
 // External crate imports, alphabetized
-use image::{ImageBuffer, Luma};
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
 use serde::{Deserialize, Serialize};
-use serde_json::Error as SerdeError;
 use std::collections::VecDeque;
+use std::fmt;
 use std::path::PathBuf;
 
 // Standard library imports, alphabetized
 use std::sync::Arc;
+use std::time::Instant;
+
+// Current crate imports
+use crate::config::{ClipFormat, OverlapPolicy};
+use crate::control_api::ControlRequest;
+use crate::settings::AppliedCameraSettings;
+
+/// Layout of the bytes in `ImageData::data`.
+///
+/// Formats deeper than 8 bits are stored as little-endian 16-bit words with
+/// the significant bits in the low end.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum PixelFormat {
+    #[default]
+    Mono8,
+    Mono10,
+    Mono12,
+    Mono16,
+    BayerRggb8,
+    BayerBggr8,
+    BayerGrbg8,
+    BayerGbrg8,
+    /// 8 bits per channel in blue, green, red order as delivered by XIMEA
+    Rgb24,
+}
+
+impl PixelFormat {
+    pub fn bytes_per_pixel(&self) -> usize {
+        match self {
+            PixelFormat::Mono10 | PixelFormat::Mono12 | PixelFormat::Mono16 => 2,
+            PixelFormat::Rgb24 => 3,
+            _ => 1,
+        }
+    }
+
+    /// Number of significant bits per pixel
+    pub fn bits_per_pixel(&self) -> u32 {
+        match self {
+            PixelFormat::Mono10 => 10,
+            PixelFormat::Mono12 => 12,
+            PixelFormat::Mono16 => 16,
+            PixelFormat::Rgb24 => 24,
+            _ => 8,
+        }
+    }
+
+    pub fn is_bayer(&self) -> bool {
+        matches!(
+            self,
+            PixelFormat::BayerRggb8
+                | PixelFormat::BayerBggr8
+                | PixelFormat::BayerGrbg8
+                | PixelFormat::BayerGbrg8
+        )
+    }
+
+    /// Matching ffmpeg `-pixel_format` for raw input
+    pub fn ffmpeg_pix_fmt(&self) -> &'static str {
+        match self {
+            PixelFormat::Mono8 => "gray",
+            PixelFormat::Mono10 => "gray10le",
+            PixelFormat::Mono12 => "gray12le",
+            PixelFormat::Mono16 => "gray16le",
+            PixelFormat::BayerRggb8 => "bayer_rggb8",
+            PixelFormat::BayerBggr8 => "bayer_bggr8",
+            PixelFormat::BayerGrbg8 => "bayer_grbg8",
+            PixelFormat::BayerGbrg8 => "bayer_gbrg8",
+            PixelFormat::Rgb24 => "bgr24",
+        }
+    }
+}
 
 #[derive(Clone, Default)]
 pub struct ImageData {
-    pub data: ImageBuffer<Luma<u8>, Vec<u8>>,
+    /// Raw pixel bytes, row-major, laid out according to `pixel_format`
+    pub data: Vec<u8>,
+    pub pixel_format: PixelFormat,
     pub width: u32,
     pub height: u32,
     pub nframe: u32,
@@ -43,16 +118,174 @@ pub struct KalmanEstimateRow {
     pub P55: f64,
 }
 
+/// What started a recording.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerSource {
+    /// `KalmanEstimateRow` from the tracker
+    Tracker,
+    /// `{"trigger": "manual"}` on a trigger topic
+    Message,
+    /// Enter pressed on stdin
+    Keyboard,
+    /// Periodic timer
+    Timer,
+    /// SIGUSR1
+    Signal,
+    /// `start_recording` on the control socket
+    Control,
+}
+
+impl fmt::Display for TriggerSource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            TriggerSource::Tracker => "tracker",
+            TriggerSource::Message => "message",
+            TriggerSource::Keyboard => "keyboard",
+            TriggerSource::Timer => "timer",
+            TriggerSource::Signal => "signal",
+            TriggerSource::Control => "control",
+        };
+        f.write_str(name)
+    }
+}
+
 // Adjusted for the enum
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub enum MessageType {
     Empty,
-    JsonData(KalmanEstimateRow),
+    /// Tracking data from a trigger topic; starts a recording
+    JsonData {
+        topic: String,
+        row: KalmanEstimateRow,
+        time: TriggerTime,
+    },
     Text(String),
-    InvalidJson(String, SerdeError), // New variant to include parsing error details
+    InvalidJson(String, String), // Raw message and the parsing error
+    /// Command from a control topic (or "kill" on a trigger topic)
+    Control {
+        topic: String,
+        command: String,
+    },
+    /// Camera parameter change from a parameter topic
+    ParameterUpdate {
+        topic: String,
+        name: String,
+        value: String,
+    },
+    /// Request from the control socket that expects an answer
+    Command(ControlRequest),
+    /// Start a recording without tracking data
+    Trigger {
+        source: TriggerSource,
+        label: Option<String>,
+        time: TriggerTime,
+    },
+    /// The camera's framerate was changed to this many frames per second
+    FramerateChanged(f32),
+    /// Shut down because a message source failed
+    Kill,
+}
+
+impl MessageType {
+    /// Whether this message asks the program to shut down.
+    pub fn is_kill(&self) -> bool {
+        match self {
+            MessageType::Text(text) => text == "kill",
+            MessageType::Control { command, .. } => command == "kill",
+            MessageType::Kill => true,
+            _ => false,
+        }
+    }
+}
+
+/// When a trigger arrived. It is taken once where the trigger is received,
+/// so every camera names its clip by the same time and starts it at the
+/// frame taken at that time.
+#[derive(Debug, Clone, Copy)]
+pub struct TriggerTime {
+    pub wallclock: DateTime<Local>,
+    pub instant: Instant,
+}
+
+impl TriggerTime {
+    pub fn now() -> Self {
+        Self {
+            wallclock: Local::now(),
+            instant: Instant::now(),
+        }
+    }
+}
+
+/// Counters and camera timestamp of a frame, to line up the clips of
+/// different cameras.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct FrameStamp {
+    pub nframe: u32,
+    pub acq_nframe: u32,
+    pub timestamp_raw: u64,
+}
+
+impl From<&ImageData> for FrameStamp {
+    fn from(image: &ImageData) -> Self {
+        Self {
+            nframe: image.nframe,
+            acq_nframe: image.acq_nframe,
+            timestamp_raw: image.timestamp_raw,
+        }
+    }
+}
+
+/// A trigger a clip was recorded for.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClipTrigger {
+    pub trigger_source: TriggerSource,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub label: Option<String>,
+    /// Local time the trigger arrived, RFC 3339
+    pub trigger_time: String,
+    /// Tracking data of tracker triggers
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub kalman: Option<KalmanEstimateRow>,
+}
+
+/// Saved next to every clip as `<clip>.meta.json`.
+#[derive(Debug, Clone, Serialize)]
+pub struct ClipMetadata {
+    pub camera: String,
+    #[serde(flatten)]
+    pub trigger: ClipTrigger,
+    /// Triggers that extended the clip under `OverlapPolicy::Extend`
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub extended_by: Vec<ClipTrigger>,
+    pub overlap_policy: OverlapPolicy,
+    pub frames: usize,
+    /// How the frames are stored
+    pub format: ClipFormat,
+    /// ffmpeg encoder of the video, filled in by the video writer like the
+    /// fields below
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub encoder: Option<String>,
+    /// Acquisition framerate measured from the frame timestamps
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub fps: Option<f64>,
+    /// Framerate the video plays at, if not `fps`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub playback_fps: Option<f32>,
+    /// Frame intervals without a frame, from frames dropped by the camera
+    pub missing_frames: u64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub first_frame: Option<FrameStamp>,
+    /// First frame taken at or after the trigger arrived
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub trigger_frame: Option<FrameStamp>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub last_frame: Option<FrameStamp>,
 }
 
 pub struct FramesPacket {
     pub images: VecDeque<Arc<ImageData>>,
     pub save_path: PathBuf,
+    pub camera_settings: Option<Arc<AppliedCameraSettings>>,
+    pub metadata: Option<ClipMetadata>,
 }
@@ -0,0 +1,275 @@
+// External crate imports
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+// Standard library imports
+use std::fmt;
+use std::path::{Component, Path, PathBuf};
+use std::str::FromStr;
+
+// Current crate imports
+use crate::structs::{KalmanEstimateRow, TriggerSource};
+
+/// Placeholders that do not come from `KalmanEstimateRow`.
+const BUILTIN_VARIABLES: [&str; 7] = [
+    "camera_serial",
+    "date",
+    "time",
+    "wallclock",
+    "trigger_time",
+    "trigger_source",
+    "label",
+];
+
+/// Clip path relative to the save folder, such as
+/// `{date}/{camera_serial}/obj{obj_id}_f{frame}_{wallclock}`.
+///
+/// A placeholder names either a `KalmanEstimateRow` field or one of
+/// `camera_serial`, `date` (YYYY-MM-DD), `time` (HH-MM-SS), `wallclock`
+/// (YYYYMMDD_HHMMSS_mmm) and `trigger_time` (Unix time in ms), all taken
+/// when the trigger arrived, `trigger_source` and `label` (the label of a
+/// manual trigger, or its source). Numbers accept a precision, e.g. `{x:.3}`.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct PathTemplate {
+    source: String,
+    parts: Vec<Part>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Part {
+    Literal(String),
+    Variable {
+        name: String,
+        precision: Option<usize>,
+    },
+}
+
+/// Everything a template can refer to for one clip.
+pub struct TriggerContext<'a> {
+    pub row: &'a KalmanEstimateRow,
+    pub camera_serial: &'a str,
+    pub trigger_time: DateTime<Local>,
+    pub source: TriggerSource,
+    pub label: Option<&'a str>,
+}
+
+impl Default for PathTemplate {
+    fn default() -> Self {
+        "obj_id_{obj_id}_frame_{frame}"
+            .parse()
+            .expect("default template is valid")
+    }
+}
+
+impl PathTemplate {
+    /// Whether the template contains the placeholder `name`.
+    pub fn uses(&self, name: &str) -> bool {
+        self.parts
+            .iter()
+            .any(|part| matches!(part, Part::Variable { name: n, .. } if n == name))
+    }
+
+    /// Render the clip path for `context`. Values can come from untrusted
+    /// messages, so each one is confined to the path component it is part
+    /// of, and a path that would still leave the save folder is refused.
+    pub fn render(&self, context: &TriggerContext) -> Result<PathBuf, String> {
+        let row = serde_json::to_value(context.row).unwrap_or_default();
+        let mut rendered = String::new();
+        for part in &self.parts {
+            let (name, precision) = match part {
+                Part::Literal(text) => {
+                    rendered.push_str(text);
+                    continue;
+                }
+                Part::Variable { name, precision } => (name.as_str(), *precision),
+            };
+            let value = match name {
+                "camera_serial" => context.camera_serial.to_string(),
+                "date" => context.trigger_time.format("%Y-%m-%d").to_string(),
+                "time" => context.trigger_time.format("%H-%M-%S").to_string(),
+                "wallclock" => context.trigger_time.format("%Y%m%d_%H%M%S_%3f").to_string(),
+                "trigger_time" => context.trigger_time.timestamp_millis().to_string(),
+                "trigger_source" => context.source.to_string(),
+                "label" => context
+                    .label
+                    .map_or_else(|| context.source.to_string(), str::to_string),
+                field => match (&row[field], precision) {
+                    (Value::Number(n), Some(precision)) => {
+                        format!("{:.*}", precision, n.as_f64().unwrap_or_default())
+                    }
+                    (Value::String(s), _) => s.clone(),
+                    (value, _) => value.to_string(),
+                },
+            };
+            rendered.push_str(&sanitize(&value));
+        }
+        let path = PathBuf::from(rendered);
+        if path.as_os_str().is_empty()
+            || path
+                .components()
+                .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(format!("Clip path {:?} is not below the save folder", path));
+        }
+        Ok(path)
+    }
+}
+
+/// Keep a rendered value within one path component: separators become
+/// `_`, control characters are dropped, and a value that is empty or names
+/// the current or parent directory is replaced by `_`.
+fn sanitize(value: &str) -> String {
+    let value: String = value
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| if matches!(c, '/' | '\\') { '_' } else { c })
+        .collect();
+    match value.as_str() {
+        "" | "." | ".." => "_".to_string(),
+        _ => value,
+    }
+}
+
+impl FromStr for PathTemplate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.trim().is_empty() {
+            return Err("Path template must not be empty".to_string());
+        }
+        if Path::new(s)
+            .components()
+            .any(|c| !matches!(c, Component::Normal(_)))
+        {
+            return Err(format!(
+                "Path template {:?} must be relative to the save folder",
+                s
+            ));
+        }
+
+        let fields = kalman_fields();
+        let mut parts = Vec::new();
+        let mut rest = s;
+        while let Some(start) = rest.find('{') {
+            if start > 0 {
+                parts.push(Part::Literal(rest[..start].to_string()));
+            }
+            let end = rest[start..]
+                .find('}')
+                .ok_or_else(|| format!("Unclosed placeholder in path template {:?}", s))?
+                + start;
+            let placeholder = &rest[start + 1..end];
+            let (name, precision) = match placeholder.split_once(":.") {
+                Some((name, precision)) => {
+                    let precision = precision
+                        .parse()
+                        .map_err(|_| format!("Invalid precision in {{{}}}", placeholder))?;
+                    (name, Some(precision))
+                }
+                None => (placeholder, None),
+            };
+            if !BUILTIN_VARIABLES.contains(&name) && !fields.iter().any(|f| f == name) {
+                return Err(format!(
+                    "Unknown placeholder {{{}}} in path template, expected one of {} or a KalmanEstimateRow field",
+                    name,
+                    BUILTIN_VARIABLES.join(", ")
+                ));
+            }
+            parts.push(Part::Variable {
+                name: name.to_string(),
+                precision,
+            });
+            rest = &rest[end + 1..];
+        }
+        if !rest.is_empty() {
+            parts.push(Part::Literal(rest.to_string()));
+        }
+
+        Ok(Self {
+            source: s.to_string(),
+            parts,
+        })
+    }
+}
+
+impl TryFrom<String> for PathTemplate {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<PathTemplate> for String {
+    fn from(template: PathTemplate) -> Self {
+        template.source
+    }
+}
+
+impl fmt::Display for PathTemplate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.source)
+    }
+}
+
+fn kalman_fields() -> Vec<String> {
+    match serde_json::to_value(KalmanEstimateRow::default()) {
+        Ok(Value::Object(fields)) => fields.into_iter().map(|(name, _)| name).collect(),
+        _ => Vec::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn render(template: &str, label: &str) -> Result<PathBuf, String> {
+        let row = KalmanEstimateRow {
+            obj_id: 7,
+            ..Default::default()
+        };
+        let context = TriggerContext {
+            row: &row,
+            camera_serial: "cam0",
+            trigger_time: Local::now(),
+            source: TriggerSource::Message,
+            label: Some(label),
+        };
+        template.parse::<PathTemplate>()?.render(&context)
+    }
+
+    #[test]
+    fn renders_placeholders() {
+        assert_eq!(
+            render("{camera_serial}/obj{obj_id}_{label}", "run1"),
+            Ok(PathBuf::from("cam0/obj7_run1"))
+        );
+    }
+
+    #[test]
+    fn labels_stay_in_their_component() {
+        for label in ["..", ".", ""] {
+            assert_eq!(
+                render("{label}/clip", label),
+                Ok(PathBuf::from("_/clip")),
+                "{:?}",
+                label
+            );
+        }
+        assert_eq!(
+            render("{label}", "../../etc/passwd"),
+            Ok(PathBuf::from(".._.._etc_passwd"))
+        );
+        assert_eq!(render("{label}", "a\0b\nc\\d"), Ok(PathBuf::from("abc_d")));
+        assert_eq!(render("{label}", ".."), Ok(PathBuf::from("_")));
+    }
+
+    #[test]
+    fn rejects_templates_leaving_the_save_folder() {
+        assert!(render("../{label}", "x").is_err());
+        assert!(render("/tmp/{label}", "x").is_err());
+        assert!(render("{label", "x").is_err());
+    }
+}
@@ -0,0 +1,123 @@
+// External crate imports
+use anyhow::{Context, Result};
+use crossbeam::channel::{bounded, Receiver, RecvTimeoutError, Sender};
+use signal_hook::consts::SIGUSR1;
+use signal_hook::iterator::{Handle, Signals};
+
+// Standard library imports
+use std::io::{self, BufRead};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Current crate imports
+use crate::config::ManualTriggerConfig;
+use crate::structs::{MessageType, TriggerSource, TriggerTime};
+
+/// Trigger sources besides the tracker, all feeding the channel the
+/// subscriber feeds.
+pub struct ManualTriggers {
+    /// Dropping this wakes the timer thread
+    stop: Option<Sender<()>>,
+    signals: Option<Handle>,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ManualTriggers {
+    /// Start every source enabled in `config`.
+    pub fn start(config: &ManualTriggerConfig, messages: &Sender<MessageType>) -> Result<Self> {
+        let (stop, stopped) = bounded::<()>(0);
+        let mut triggers = Self {
+            stop: Some(stop),
+            signals: None,
+            threads: Vec::new(),
+        };
+
+        if config.signal {
+            let signals = Signals::new([SIGUSR1]).context("Failed to handle SIGUSR1")?;
+            triggers.signals = Some(signals.handle());
+            let messages = messages.clone();
+            triggers
+                .threads
+                .push(thread::spawn(move || signal_trigger(signals, messages)));
+            log::info!("Recording on SIGUSR1 (kill -USR1 {})", std::process::id());
+        }
+        if let Some(interval_s) = config.interval_s {
+            let interval = Duration::from_secs_f32(interval_s);
+            let messages = messages.clone();
+            triggers.threads.push(thread::spawn(move || {
+                timer_trigger(interval, stopped, messages)
+            }));
+            log::info!("Recording every {} s", interval_s);
+        }
+        if config.keyboard {
+            // blocked reading stdin, so this thread is never joined
+            let messages = messages.clone();
+            thread::spawn(move || keyboard_trigger(messages));
+            log::info!("Recording when Enter is pressed, type a label first to name the clip");
+        }
+
+        Ok(triggers)
+    }
+
+    /// Stop the timer and signal sources and wait for them.
+    pub fn stop(mut self) {
+        self.stop.take();
+        if let Some(signals) = self.signals.take() {
+            signals.close();
+        }
+        for thread in self.threads.drain(..) {
+            thread.join().unwrap();
+        }
+    }
+}
+
+fn send_trigger(
+    messages: &Sender<MessageType>,
+    source: TriggerSource,
+    label: Option<String>,
+) -> bool {
+    log::debug!("{} trigger", source);
+    messages
+        .send(MessageType::Trigger {
+            source,
+            label,
+            time: TriggerTime::now(),
+        })
+        .is_ok()
+}
+
+/// Each line entered on stdin triggers a recording labelled with the line,
+/// unless it is empty. "kill" shuts down like the ZeroMQ message.
+fn keyboard_trigger(messages: Sender<MessageType>) {
+    for line in io::stdin().lock().lines() {
+        let Ok(line) = line else {
+            break;
+        };
+        let line = line.trim();
+        let sent = if line == "kill" {
+            messages.send(MessageType::Text(line.to_string())).is_ok()
+        } else {
+            let label = (!line.is_empty()).then(|| line.to_string());
+            send_trigger(&messages, TriggerSource::Keyboard, label)
+        };
+        if !sent {
+            break;
+        }
+    }
+}
+
+fn timer_trigger(interval: Duration, stop: Receiver<()>, messages: Sender<MessageType>) {
+    while let Err(RecvTimeoutError::Timeout) = stop.recv_timeout(interval) {
+        if !send_trigger(&messages, TriggerSource::Timer, None) {
+            break;
+        }
+    }
+}
+
+fn signal_trigger(mut signals: Signals, messages: Sender<MessageType>) {
+    for _ in signals.forever() {
+        if !send_trigger(&messages, TriggerSource::Signal, None) {
+            break;
+        }
+    }
+}
@@ -0,0 +1,410 @@
+use clap::ValueEnum;
+use serde::{Deserialize, Serialize};
+
+use std::fmt;
+use std::str::FromStr;
+
+use crate::structs::PixelFormat;
+
+/// Highest GPI/GPO line number accepted by `CameraConfig::validate`.
+const MAX_IO_LINE: u32 = 12;
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(tag = "mode", rename_all = "snake_case")]
+pub enum ExposureMode {
+    /// Fixed exposure from `CameraConfig::exposure`, clamped to the frame period
+    #[default]
+    Manual,
+    /// Camera-controlled auto exposure/auto gain (AEAG)
+    Auto(AutoExposureConfig),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct AutoExposureConfig {
+    /// Longest exposure AEAG may choose, in µs
+    pub max_exposure_us: f32,
+    /// Highest gain AEAG may choose, in dB
+    pub max_gain_db: f32,
+    /// Target average intensity, in percent of full scale
+    pub target_level: f32,
+    /// Share of the correction done with exposure rather than gain, 0 to 1
+    pub priority: f32,
+}
+
+impl Default for AutoExposureConfig {
+    fn default() -> Self {
+        Self {
+            max_exposure_us: 2000.0,
+            max_gain_db: 12.0,
+            target_level: 75.0,
+            priority: 1.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum BinningMode {
+    /// Add up the binned pixels, trading resolution for sensitivity
+    #[default]
+    Sum,
+    /// Average the binned pixels, trading resolution for noise
+    Average,
+}
+
+/// Sensor binning factors; 1 disables binning on that axis.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct BinningConfig {
+    pub horizontal: u32,
+    pub vertical: u32,
+    pub mode: BinningMode,
+}
+
+impl Default for BinningConfig {
+    fn default() -> Self {
+        Self {
+            horizontal: 1,
+            vertical: 1,
+            mode: BinningMode::Sum,
+        }
+    }
+}
+
+/// Sensor decimation (line/column skipping) factors; 1 disables decimation.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DecimationConfig {
+    pub horizontal: u32,
+    pub vertical: u32,
+}
+
+impl Default for DecimationConfig {
+    fn default() -> Self {
+        Self {
+            horizontal: 1,
+            vertical: 1,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, ValueEnum)]
+#[serde(rename_all = "snake_case")]
+pub enum TriggerMode {
+    /// Free-running acquisition limited to `fps`
+    #[default]
+    FreeRun,
+    /// One frame per rising edge on the trigger GPI line
+    RisingEdge,
+    /// One frame per falling edge on the trigger GPI line
+    FallingEdge,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TriggerConfig {
+    pub mode: TriggerMode,
+    /// GPI line the sync pulse is wired to
+    pub gpi_line: u32,
+    /// Delay between the trigger edge and the start of exposure, in µs
+    pub delay_us: u32,
+}
+
+impl Default for TriggerConfig {
+    fn default() -> Self {
+        Self {
+            mode: TriggerMode::FreeRun,
+            gpi_line: 1,
+            delay_us: 0,
+        }
+    }
+}
+
+impl fmt::Display for TriggerConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.mode {
+            TriggerMode::FreeRun => write!(f, "free-running"),
+            TriggerMode::RisingEdge | TriggerMode::FallingEdge => write!(
+                f,
+                "{} edge on GPI {}, delay {} µs",
+                if self.mode == TriggerMode::RisingEdge {
+                    "rising"
+                } else {
+                    "falling"
+                },
+                self.gpi_line,
+                self.delay_us
+            ),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StrobeMode {
+    /// High while the sensor is exposing
+    ExposureActive,
+    /// High while a frame is being acquired (exposure and readout)
+    FrameActive,
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StrobeConfig {
+    pub gpo_line: u32,
+    pub mode: StrobeMode,
+    #[serde(default)]
+    pub inverted: bool,
+}
+
+impl fmt::Display for StrobeConfig {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mode = match self.mode {
+            StrobeMode::ExposureActive => "exposure active",
+            StrobeMode::FrameActive => "frame active",
+        };
+        write!(f, "GPO {} {}", self.gpo_line, mode)?;
+        if self.inverted {
+            write!(f, " (inverted)")?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses `LINE:MODE[:inverted]`, e.g. `2:exposure-active:inverted`.
+impl FromStr for StrobeConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split(':');
+        let gpo_line = parts
+            .next()
+            .and_then(|line| line.trim().parse().ok())
+            .ok_or_else(|| format!("Invalid strobe line in {:?}", s))?;
+        let mode = match parts.next().map(str::trim) {
+            Some("exposure-active") => StrobeMode::ExposureActive,
+            Some("frame-active") => StrobeMode::FrameActive,
+            _ => {
+                return Err(format!(
+                    "Strobe mode in {:?} must be exposure-active or frame-active",
+                    s
+                ))
+            }
+        };
+        let inverted = match parts.next().map(str::trim) {
+            None => false,
+            Some("inverted") => true,
+            Some(other) => return Err(format!("Unknown strobe option {:?}", other)),
+        };
+        Ok(Self {
+            gpo_line,
+            mode,
+            inverted,
+        })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct CameraConfig {
+    /// Sensor serial number as printed on the camera. `None` opens the
+    /// first attached device.
+    pub serial: Option<String>,
+    pub fps: f32,
+    /// Exposure time in µs, used in manual exposure mode
+    pub exposure: f32,
+    pub exposure_mode: ExposureMode,
+    pub width: u32,
+    pub height: u32,
+    pub offset_x: u32,
+    pub offset_y: u32,
+    /// Ignore the offsets and center the ROI on the sensor
+    pub center_roi: bool,
+    pub binning: BinningConfig,
+    pub decimation: DecimationConfig,
+    pub pixel_format: PixelFormat,
+    pub trigger: TriggerConfig,
+    pub strobes: Vec<StrobeConfig>,
+    /// Fail startup when the applied framerate/exposure differ from the
+    /// request by more than this relative amount (e.g. 0.05 for 5 %)
+    pub settings_tolerance: Option<f32>,
+}
+
+impl Default for CameraConfig {
+    fn default() -> Self {
+        Self {
+            serial: None,
+            fps: 500.0,
+            exposure: 2000.0,
+            exposure_mode: ExposureMode::Manual,
+            width: 2016,
+            height: 2016,
+            offset_x: 1056,
+            offset_y: 170,
+            center_roi: false,
+            binning: BinningConfig::default(),
+            decimation: DecimationConfig::default(),
+            pixel_format: PixelFormat::Mono8,
+            trigger: TriggerConfig::default(),
+            strobes: Vec::new(),
+            settings_tolerance: None,
+        }
+    }
+}
+
+impl CameraConfig {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_serial(mut self, serial: impl Into<String>) -> Self {
+        self.serial = Some(serial.into());
+        self
+    }
+
+    pub fn with_fps(mut self, fps: f32) -> Self {
+        self.fps = fps;
+        self
+    }
+
+    pub fn with_exposure(mut self, exposure: f32) -> Self {
+        self.exposure = exposure;
+        self
+    }
+
+    pub fn with_exposure_mode(mut self, exposure_mode: ExposureMode) -> Self {
+        self.exposure_mode = exposure_mode;
+        self
+    }
+
+    pub fn with_resolution(mut self, width: u32, height: u32) -> Self {
+        self.width = width;
+        self.height = height;
+        self
+    }
+
+    pub fn with_offset(mut self, offset_x: u32, offset_y: u32) -> Self {
+        self.offset_x = offset_x;
+        self.offset_y = offset_y;
+        self
+    }
+
+    pub fn with_centered_roi(mut self, center_roi: bool) -> Self {
+        self.center_roi = center_roi;
+        self
+    }
+
+    pub fn with_binning(mut self, binning: BinningConfig) -> Self {
+        self.binning = binning;
+        self
+    }
+
+    pub fn with_decimation(mut self, decimation: DecimationConfig) -> Self {
+        self.decimation = decimation;
+        self
+    }
+
+    pub fn with_pixel_format(mut self, pixel_format: PixelFormat) -> Self {
+        self.pixel_format = pixel_format;
+        self
+    }
+
+    pub fn with_trigger(mut self, trigger: TriggerConfig) -> Self {
+        self.trigger = trigger;
+        self
+    }
+
+    pub fn with_strobes(mut self, strobes: Vec<StrobeConfig>) -> Self {
+        self.strobes = strobes;
+        self
+    }
+
+    pub fn with_settings_tolerance(mut self, tolerance: Option<f32>) -> Self {
+        self.settings_tolerance = tolerance;
+        self
+    }
+
+    /// `exposure` in µs as the camera is set to it, shortened to fit in the
+    /// frame period at `fps`.
+    pub fn clamp_exposure(&self, exposure: f32) -> f32 {
+        let max_exposure_for_fps = 1_000_000_f32 / self.fps;
+
+        // if the exposure is greater than the max exposure for the fps
+        // return the max exposure (-1.0 to make sure it's short enough) possible for the fps
+        // otherwise return the original exposure
+        if exposure > max_exposure_for_fps {
+            max_exposure_for_fps - 1.0
+        } else {
+            exposure
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.serial.as_deref().is_some_and(|s| s.trim().is_empty()) {
+            return Err("Serial number must not be empty".to_string());
+        }
+        if !self.fps.is_finite() || self.fps <= 0.0 {
+            return Err("FPS must be a finite number greater than 0".to_string());
+        }
+        if !self.exposure.is_finite() || self.exposure <= 0.0 {
+            return Err("Exposure must be a finite number greater than 0".to_string());
+        }
+        if self
+            .settings_tolerance
+            .is_some_and(|t| !t.is_finite() || t < 0.0)
+        {
+            return Err("Settings tolerance must be a finite, non-negative number".to_string());
+        }
+        if let ExposureMode::Auto(auto) = &self.exposure_mode {
+            if !auto.max_exposure_us.is_finite() || auto.max_exposure_us <= 0.0 {
+                return Err(
+                    "Auto exposure limit must be a finite number greater than 0".to_string()
+                );
+            }
+            if !auto.max_gain_db.is_finite() || auto.max_gain_db < 0.0 {
+                return Err("Auto gain limit must be a finite, non-negative number".to_string());
+            }
+            if !(0.0..=100.0).contains(&auto.target_level) {
+                return Err("Auto exposure target level must be between 0 and 100".to_string());
+            }
+            if !(0.0..=1.0).contains(&auto.priority) {
+                return Err("Exposure priority must be between 0 and 1".to_string());
+            }
+        }
+        if self.width == 0 || self.height == 0 {
+            return Err("Resolution must be greater than 0".to_string());
+        }
+        if self.binning.horizontal == 0 || self.binning.vertical == 0 {
+            return Err("Binning factors must be at least 1".to_string());
+        }
+        if self.decimation.horizontal == 0 || self.decimation.vertical == 0 {
+            return Err("Decimation factors must be at least 1".to_string());
+        }
+        if self.trigger.mode != TriggerMode::FreeRun
+            && !(1..=MAX_IO_LINE).contains(&self.trigger.gpi_line)
+        {
+            return Err(format!(
+                "Trigger GPI line must be between 1 and {}",
+                MAX_IO_LINE
+            ));
+        }
+        for (i, strobe) in self.strobes.iter().enumerate() {
+            if !(1..=MAX_IO_LINE).contains(&strobe.gpo_line) {
+                return Err(format!(
+                    "Strobe GPO line must be between 1 and {}",
+                    MAX_IO_LINE
+                ));
+            }
+            if self.strobes[..i]
+                .iter()
+                .any(|other| other.gpo_line == strobe.gpo_line)
+            {
+                return Err(format!(
+                    "GPO line {} is configured more than once",
+                    strobe.gpo_line
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -1,91 +1,648 @@
-use anyhow::{Context, Result};
-use log::{info, debug, error};
-use xiapi;
+use image::{ImageBuffer, Luma};
+use log::{debug, error, info, warn};
 
-use crate::camera::CameraConfig;
+use super::error::{CameraError, XiResultExt};
+use super::params;
+use super::{
+    find_device, BinningConfig, BinningMode, CameraCapabilities, CameraConfig, DecimationConfig,
+    ExposureMode, StrobeConfig, StrobeMode, TriggerConfig, TriggerMode,
+};
+use crate::backend::CameraBackend;
+use crate::settings::AppliedCameraSettings;
+use crate::structs::{ImageData, PixelFormat};
+
+/// The xiapi handle changes type when acquisition starts, so keep track of
+/// which one we currently own.
+enum DeviceState {
+    Idle(xiapi::Camera),
+    Acquiring(xiapi::AcquisitionBuffer),
+}
 
 pub struct XimeaCamera {
-    device: xiapi::Camera,
+    state: Option<DeviceState>,
+    device_index: u32,
+    pixel_format: PixelFormat,
+    /// Limits which parameters may change while running
+    trigger_mode: TriggerMode,
+    auto_exposure: bool,
 }
 
 impl XimeaCamera {
-    pub fn new(config: &CameraConfig) -> result<Self> {
-        info!("Initializing XIMEA camera");
-        let mut device = xiapi::open_device(Some(config.serial)).context("Failed to open XIMEA camera");
-        let camera = Self { device };
-        camera.configure(config)?;
+    pub fn new(config: &CameraConfig) -> Result<Self, CameraError> {
+        let mut camera = Self::open(config.serial.as_deref())?;
+        camera.apply_config(config)?;
         Ok(camera)
     }
 
-    fn configure(&self, config: &CameraConfig) -> Result<()> {
+    /// Open a camera without touching its configuration.
+    pub fn open(serial: Option<&str>) -> Result<Self, CameraError> {
+        let index = match serial {
+            Some(serial) => {
+                let info = find_device(serial)?;
+                info!(
+                    "Initializing XIMEA camera {} ({}) at device index {}",
+                    info.serial, info.model, info.index
+                );
+                info.index
+            }
+            None => {
+                if params::number_devices()? == 0 {
+                    return Err(CameraError::NoDevices);
+                }
+                info!("Initializing first attached XIMEA camera");
+                0
+            }
+        };
+        let device = xiapi::open_device(Some(index)).map_err(CameraError::Open)?;
+        Ok(Self {
+            state: Some(DeviceState::Idle(device)),
+            device_index: index,
+            pixel_format: PixelFormat::Mono8,
+            trigger_mode: TriggerMode::FreeRun,
+            auto_exposure: false,
+        })
+    }
+
+    /// Query sensor size, supported formats and framerate/exposure limits.
+    ///
+    /// Probing the image formats leaves the camera in its original format.
+    pub fn capabilities(&mut self) -> Result<CameraCapabilities, CameraError> {
+        let device = self.device()?;
+
+        let sensor_width = params::get_int(
+            device,
+            &params::info(xiapi::XI_PRM_WIDTH, xiapi::XI_PRM_INFO_MAX),
+        )? as u32;
+        let sensor_height = params::get_int(
+            device,
+            &params::info(xiapi::XI_PRM_HEIGHT, xiapi::XI_PRM_INFO_MAX),
+        )? as u32;
+
+        let original_format = params::get_int(device, xiapi::XI_PRM_IMAGE_DATA_FORMAT)?;
+        let image_formats = IMAGE_FORMATS
+            .iter()
+            .filter(|(format, _)| {
+                params::set_int(device, xiapi::XI_PRM_IMAGE_DATA_FORMAT, *format as i32).is_ok()
+            })
+            .map(|(_, name)| *name)
+            .collect();
+        params::set_int(device, xiapi::XI_PRM_IMAGE_DATA_FORMAT, original_format)?;
+
+        let roi = device.roi().or_xi("read ROI")?;
+
+        Ok(CameraCapabilities {
+            sensor_width,
+            sensor_height,
+            image_formats,
+            roi: (roi.width, roi.height),
+            max_framerate: device.framerate_maximum().or_xi("read maximum framerate")?,
+            exposure_min_us: device.exposure_minimum().or_xi("read minimum exposure")?,
+            exposure_max_us: device.exposure_maximum().or_xi("read maximum exposure")?,
+        })
+    }
+
+    /// Read back ROI, timing, gain and buffer settings from the device.
+    ///
+    /// The sensor temperature is optional because not every model has a
+    /// readable sensor.
+    pub fn applied_settings(&mut self) -> Result<AppliedCameraSettings, CameraError> {
+        let device_index = self.device_index;
+        let pixel_format = self.pixel_format;
+        let device = self.device()?;
+
+        let roi = device.roi().or_xi("read ROI")?;
+        let settings = AppliedCameraSettings {
+            serial: params::device_info_string(device_index, xiapi::XI_PRM_DEVICE_SN).ok(),
+            model: params::device_info_string(device_index, xiapi::XI_PRM_DEVICE_NAME).ok(),
+            width: roi.width,
+            height: roi.height,
+            offset_x: roi.offset_x,
+            offset_y: roi.offset_y,
+            pixel_format,
+            framerate: device.framerate().or_xi("read framerate")?,
+            exposure_us: device.exposure().or_xi("read exposure")?,
+            gain_db: device.gain().or_xi("read gain")?,
+            bandwidth_limit_mbps: device.limit_bandwidth().or_xi("read bandwidth limit")?,
+            acq_buffer_size: device
+                .acq_buffer_size()
+                .or_xi("read acquisition buffer size")?,
+            buffers_queue_size: device
+                .buffers_queue_size()
+                .or_xi("read buffers queue size")?,
+            sensor_temperature_c: params::get_float(device, xiapi::XI_PRM_CHIP_TEMP).ok(),
+        };
+        Ok(settings)
+    }
+
+    fn device(&mut self) -> Result<&mut xiapi::Camera, CameraError> {
+        match self.state.as_mut() {
+            Some(DeviceState::Idle(device)) => Ok(device),
+            Some(DeviceState::Acquiring(buffer)) => Ok(buffer),
+            None => Err(CameraError::HandleLost),
+        }
+    }
+
+    fn apply_config(&mut self, config: &CameraConfig) -> Result<(), CameraError> {
         debug!("Configuring camera parameters");
 
-        self.set_resolution(config.width, config.height, config.offset_x, config.offset_y)?;
-        self.set_framerate(config.fps)?;
-        self.set_exposure(config.exposure)?;
-        self.set_image_format()?;
+        // binning and decimation change the sensor size the ROI is relative to
+        self.set_binning(&config.binning)?;
+        self.set_decimation(&config.decimation)?;
+        self.set_resolution(config)?;
+        self.set_image_format(config.pixel_format)?;
+        self.setup_trigger(&config.trigger)?;
+        if config.trigger.mode == TriggerMode::FreeRun {
+            self.set_framerate(config.fps)?;
+        }
+        self.setup_strobes(&config.strobes)?;
         self.optimize_buffers()?;
+        self.setup_exposure(config)?;
+        self.trigger_mode = config.trigger.mode;
+        self.auto_exposure = matches!(config.exposure_mode, ExposureMode::Auto(_));
 
-        self.device.reset_frame()?;
+        self.device()?
+            .recent_frame()
+            .or_xi("select recent frame mode")?;
 
         info!("Camera configuration complete");
         Ok(())
     }
 
-    fn set_resolution(&self, width: u32, height: u32, offset_x: u32, offset_y: u32) -> Result<()> {
-        let roi = xiapi::Roi{
+    /// Set the binning factors, 1x1 included, so none is left over from
+    /// an earlier run.
+    fn set_binning(&mut self, binning: &BinningConfig) -> Result<(), CameraError> {
+        let device = self.device()?;
+        let unbinned = binning.horizontal == 1 && binning.vertical == 1;
+        let mode = match binning.mode {
+            BinningMode::Sum => xiapi::XI_BIN_MODE::XI_BIN_MODE_SUM,
+            BinningMode::Average => xiapi::XI_BIN_MODE::XI_BIN_MODE_AVERAGE,
+        };
+        let apply = || -> Result<(), CameraError> {
+            params::set_int(
+                device,
+                xiapi::XI_PRM_BINNING_SELECTOR,
+                xiapi::XI_BIN_SELECTOR::XI_BIN_SELECT_SENSOR as i32,
+            )?;
+            if !unbinned {
+                params::set_int(device, xiapi::XI_PRM_BINNING_HORIZONTAL_MODE, mode as i32)?;
+                params::set_int(device, xiapi::XI_PRM_BINNING_VERTICAL_MODE, mode as i32)?;
+            }
+            params::set_int(
+                device,
+                xiapi::XI_PRM_BINNING_HORIZONTAL,
+                binning.horizontal as i32,
+            )?;
+            params::set_int(
+                device,
+                xiapi::XI_PRM_BINNING_VERTICAL,
+                binning.vertical as i32,
+            )
+        };
+        match apply() {
+            // cameras without binning have nothing to reset
+            Err(e) if unbinned => {
+                debug!("Binning not reset: {}", e);
+                return Ok(());
+            }
+            result => result?,
+        }
+        info!(
+            "Binning: {}x{} ({:?})",
+            binning.horizontal, binning.vertical, binning.mode
+        );
+        Ok(())
+    }
+
+    /// Set the decimation factors, 1x1 included, so none is left over
+    /// from an earlier run.
+    fn set_decimation(&mut self, decimation: &DecimationConfig) -> Result<(), CameraError> {
+        let device = self.device()?;
+        let apply = || -> Result<(), CameraError> {
+            params::set_int(
+                device,
+                xiapi::XI_PRM_DECIMATION_SELECTOR,
+                xiapi::XI_DEC_SELECTOR::XI_DEC_SELECT_SENSOR as i32,
+            )?;
+            params::set_int(
+                device,
+                xiapi::XI_PRM_DECIMATION_HORIZONTAL,
+                decimation.horizontal as i32,
+            )?;
+            params::set_int(
+                device,
+                xiapi::XI_PRM_DECIMATION_VERTICAL,
+                decimation.vertical as i32,
+            )
+        };
+        match apply() {
+            // cameras without decimation have nothing to reset
+            Err(e) if decimation.horizontal == 1 && decimation.vertical == 1 => {
+                debug!("Decimation not reset: {}", e);
+                return Ok(());
+            }
+            result => result?,
+        }
+        info!(
+            "Decimation: {}x{}",
+            decimation.horizontal, decimation.vertical
+        );
+        Ok(())
+    }
+
+    /// Validate the requested ROI against the sensor size and the camera's
+    /// increments, apply it and return the ROI the camera actually uses.
+    fn set_resolution(&mut self, config: &CameraConfig) -> Result<xiapi::Roi, CameraError> {
+        let device = self.device()?;
+
+        // the maximum width/height depend on the current offsets
+        params::set_int(device, xiapi::XI_PRM_OFFSET_X, 0)?;
+        params::set_int(device, xiapi::XI_PRM_OFFSET_Y, 0)?;
+        let limits = RoiLimits::read(device)?;
+
+        let (offset_x, offset_y) = if config.center_roi {
+            (
+                centered_offset(limits.max_width, config.width, limits.offset_x_inc),
+                centered_offset(limits.max_height, config.height, limits.offset_y_inc),
+            )
+        } else {
+            (config.offset_x, config.offset_y)
+        };
+        let roi = xiapi::Roi {
             offset_x,
             offset_y,
-            width,
-            height,
+            width: config.width,
+            height: config.height,
         };
-        self.device.set_roi(&roi).context("Failed to set camera resolution")?;
-        debug!("Resolution set to {}x{} with offset ({}, {})", width, height, offset_x, offset_y);
+        limits.validate(&roi)?;
+
+        let actual_roi = device.set_roi(&roi).or_xi("set camera resolution")?;
+        info!(
+            "ROI: {}x{} at offset ({}, {}) on a {}x{} sensor",
+            actual_roi.width,
+            actual_roi.height,
+            actual_roi.offset_x,
+            actual_roi.offset_y,
+            limits.max_width,
+            limits.max_height
+        );
+        let as_tuple = |roi: &xiapi::Roi| (roi.width, roi.height, roi.offset_x, roi.offset_y);
+        if as_tuple(&actual_roi) != as_tuple(&roi) {
+            warn!(
+                "Camera adjusted the requested ROI {}x{} at ({}, {})",
+                roi.width, roi.height, roi.offset_x, roi.offset_y
+            );
+        }
+        Ok(actual_roi)
+    }
+
+    fn set_framerate(&mut self, fps: f32) -> Result<(), CameraError> {
+        let device = self.device()?;
+        device
+            .set_acq_timing_mode(xiapi::XI_ACQ_TIMING_MODE::XI_ACQ_TIMING_MODE_FRAME_RATE_LIMIT)
+            .or_xi("set framerate mode")?;
+        device.set_framerate(fps).or_xi("set framerate")?;
+        debug!("Framerate set to {} fps", fps);
         Ok(())
     }
 
-    fn set_framerate(&self, fps: f32) -> Result<()> {
-        self.device.set_acq_timing_mode(xiapi::XI_ACQ_TIMING_MODE::XI_ACQ_TIMING_MODE_FRAME_RATE_LIMIT).context("Failed to set framerate mode")?;
-        self.device.set_framerate(fps).context("Failed to set framerate")?;
+    fn setup_trigger(&mut self, trigger: &TriggerConfig) -> Result<(), CameraError> {
+        let device = self.device()?;
+        let source = match trigger.mode {
+            TriggerMode::FreeRun => {
+                params::set_int(
+                    device,
+                    xiapi::XI_PRM_TRG_SOURCE,
+                    xiapi::XI_TRG_SOURCE::XI_TRG_OFF as i32,
+                )?;
+                info!("Trigger: free-running");
+                return Ok(());
+            }
+            TriggerMode::RisingEdge => xiapi::XI_TRG_SOURCE::XI_TRG_EDGE_RISING,
+            TriggerMode::FallingEdge => xiapi::XI_TRG_SOURCE::XI_TRG_EDGE_FALLING,
+        };
+
+        // frames are paced by the sync pulse, so the framerate limit must be off
+        device
+            .set_acq_timing_mode(xiapi::XI_ACQ_TIMING_MODE::XI_ACQ_TIMING_MODE_FREE_RUN)
+            .or_xi("set free-run timing mode")?;
+        params::set_int(device, xiapi::XI_PRM_GPI_SELECTOR, trigger.gpi_line as i32)?;
+        params::set_int(
+            device,
+            xiapi::XI_PRM_GPI_MODE,
+            xiapi::XI_GPI_MODE::XI_GPI_TRIGGER as i32,
+        )?;
+        params::set_int(device, xiapi::XI_PRM_TRG_SOURCE, source as i32)?;
+        params::set_int(device, xiapi::XI_PRM_TRG_DELAY, trigger.delay_us as i32)?;
+
+        info!("Trigger: {}", trigger);
         Ok(())
     }
 
-    fn set_exposure(&self, exposure: f32) -> Result<()> {
-        self.device.set_exposure(exposure).context("Failed to set exposure")?;
-        debug!("Exposure set to {} µs", exposure);
+    fn setup_strobes(&mut self, strobes: &[StrobeConfig]) -> Result<(), CameraError> {
+        let device = self.device()?;
+        for strobe in strobes {
+            let mode = match (strobe.mode, strobe.inverted) {
+                (StrobeMode::ExposureActive, false) => xiapi::XI_GPO_MODE::XI_GPO_EXPOSURE_ACTIVE,
+                (StrobeMode::ExposureActive, true) => {
+                    xiapi::XI_GPO_MODE::XI_GPO_EXPOSURE_ACTIVE_NEG
+                }
+                (StrobeMode::FrameActive, false) => xiapi::XI_GPO_MODE::XI_GPO_FRAME_ACTIVE,
+                (StrobeMode::FrameActive, true) => xiapi::XI_GPO_MODE::XI_GPO_FRAME_ACTIVE_NEG,
+            };
+            params::set_int(device, xiapi::XI_PRM_GPO_SELECTOR, strobe.gpo_line as i32)?;
+            params::set_int(device, xiapi::XI_PRM_GPO_MODE, mode as i32)?;
+            info!("Strobe: {}", strobe);
+        }
         Ok(())
     }
 
-    fn set_image_format(&self) -> Result<()> {
-        self.device.set_image_data_format(xiapi::XI_IMG_FORMAT::XI_MONO8)
-            .context("Failed to set image format to MONO8")?;
-        debug!("Image format set to MONO8");
+    fn set_image_format(&mut self, pixel_format: PixelFormat) -> Result<(), CameraError> {
+        let device = self.device()?;
+        let (format, bit_depth) = match pixel_format {
+            PixelFormat::Mono8 => (xiapi::XI_IMG_FORMAT::XI_MONO8, None),
+            PixelFormat::Mono10 => (xiapi::XI_IMG_FORMAT::XI_MONO16, Some(10)),
+            PixelFormat::Mono12 => (xiapi::XI_IMG_FORMAT::XI_MONO16, Some(12)),
+            PixelFormat::Mono16 => (xiapi::XI_IMG_FORMAT::XI_MONO16, Some(16)),
+            PixelFormat::BayerRggb8
+            | PixelFormat::BayerBggr8
+            | PixelFormat::BayerGrbg8
+            | PixelFormat::BayerGbrg8 => (xiapi::XI_IMG_FORMAT::XI_RAW8, None),
+            PixelFormat::Rgb24 => (xiapi::XI_IMG_FORMAT::XI_RGB24, None),
+        };
+
+        if pixel_format.is_bayer() {
+            let cfa = params::get_int(device, xiapi::XI_PRM_COLOR_FILTER_ARRAY)? as u32;
+            let actual = match cfa {
+                xiapi::XI_COLOR_FILTER_ARRAY::XI_CFA_BAYER_RGGB => Some(PixelFormat::BayerRggb8),
+                xiapi::XI_COLOR_FILTER_ARRAY::XI_CFA_BAYER_BGGR => Some(PixelFormat::BayerBggr8),
+                xiapi::XI_COLOR_FILTER_ARRAY::XI_CFA_BAYER_GRBG => Some(PixelFormat::BayerGrbg8),
+                xiapi::XI_COLOR_FILTER_ARRAY::XI_CFA_BAYER_GBRG => Some(PixelFormat::BayerGbrg8),
+                _ => None,
+            };
+            if actual != Some(pixel_format) {
+                return Err(CameraError::ColorFilterMismatch {
+                    requested: pixel_format,
+                    actual: actual.map_or("none", |format| format.ffmpeg_pix_fmt()),
+                });
+            }
+        }
+
+        device
+            .set_image_data_format(format)
+            .or_xi("set image format")?;
+        if let Some(bit_depth) = bit_depth {
+            params::set_int(device, xiapi::XI_PRM_OUTPUT_DATA_BIT_DEPTH, bit_depth)?;
+        }
+        self.pixel_format = pixel_format;
+        debug!("Image format set to {:?}", pixel_format);
         Ok(())
     }
 
-    fn optimize_buffers(&self) -> Result<()> {
-        let max_bandwidth = self.device.limit_bandwidth_maximum()?;
-        self.device.set_limit_bandwidth(max_bandwidth)
-            .context("Failed to set bandwidth limit")?;
+    fn optimize_buffers(&mut self) -> Result<(), CameraError> {
+        let device = self.device()?;
+
+        let max_bandwidth = device
+            .limit_bandwidth_maximum()
+            .or_xi("read maximum bandwidth")?;
+        device
+            .set_limit_bandwidth(max_bandwidth)
+            .or_xi("set bandwidth limit")?;
 
-        let buffer_size = self.device.acq_buffer_size()?;
-        self.device.set_acq_buffer_size(buffer_size * 4)
-            .context("Failed to set acquisition buffer size")?;
+        let buffer_size = device
+            .acq_buffer_size()
+            .or_xi("read acquisition buffer size")?;
+        device
+            .set_acq_buffer_size(buffer_size * 4)
+            .or_xi("set acquisition buffer size")?;
 
-        let max_queue_size = self.device.buffers_queue_size_maximum()?;
-        self.device.set_buffers_queue_size(max_queue_size)
-            .context("Failed to set buffers queue size")?;
+        let max_queue_size = device
+            .buffers_queue_size_maximum()
+            .or_xi("read maximum buffers queue size")?;
+        device
+            .set_buffers_queue_size(max_queue_size)
+            .or_xi("set buffers queue size")?;
 
         debug!("Buffers parameters optimized");
         Ok(())
     }
 
-    fn start_acquisition(&self) -> Result<()> {
-        self.device.start_acquisition().context("Failed to start acquisition")
+    fn setup_exposure(&mut self, config: &CameraConfig) -> Result<(), CameraError> {
+        let device = self.device()?;
+        match &config.exposure_mode {
+            ExposureMode::Manual => {
+                params::set_int(device, xiapi::XI_PRM_AEAG, xiapi::XI_SWITCH::XI_OFF as i32)?;
+                let exposure = config.clamp_exposure(config.exposure);
+                if exposure != config.exposure {
+                    warn!(
+                        "Exposure of {} µs is too long for {} fps, using {} µs",
+                        config.exposure, config.fps, exposure
+                    );
+                }
+                device.set_exposure(exposure).or_xi("set exposure")?;
+                info!("Exposure: manual, {} µs", exposure);
+            }
+            ExposureMode::Auto(auto) => {
+                let max_exposure = config.clamp_exposure(auto.max_exposure_us);
+                params::set_float(device, xiapi::XI_PRM_EXP_PRIORITY, auto.priority)?;
+                params::set_int(device, xiapi::XI_PRM_AE_MAX_LIMIT, max_exposure as i32)?;
+                params::set_float(device, xiapi::XI_PRM_AG_MAX_LIMIT, auto.max_gain_db)?;
+                params::set_float(device, xiapi::XI_PRM_AEAG_LEVEL, auto.target_level)?;
+                params::set_int(device, xiapi::XI_PRM_AEAG, xiapi::XI_SWITCH::XI_ON as i32)?;
+                info!(
+                    "Exposure: auto, up to {} µs and {} dB, target level {}%, priority {}",
+                    max_exposure, auto.max_gain_db, auto.target_level, auto.priority
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Formats probed by `XimeaCamera::capabilities`.
+const IMAGE_FORMATS: [(xiapi::XI_IMG_FORMAT::Type, &str); 6] = [
+    (xiapi::XI_IMG_FORMAT::XI_MONO8, "MONO8"),
+    (xiapi::XI_IMG_FORMAT::XI_MONO16, "MONO16"),
+    (xiapi::XI_IMG_FORMAT::XI_RAW8, "RAW8"),
+    (xiapi::XI_IMG_FORMAT::XI_RAW16, "RAW16"),
+    (xiapi::XI_IMG_FORMAT::XI_RGB24, "RGB24"),
+    (xiapi::XI_IMG_FORMAT::XI_RGB32, "RGB32"),
+];
+
+/// Sensor size (after binning/decimation) and ROI step sizes.
+struct RoiLimits {
+    max_width: u32,
+    max_height: u32,
+    width_inc: u32,
+    height_inc: u32,
+    offset_x_inc: u32,
+    offset_y_inc: u32,
+}
+
+impl RoiLimits {
+    fn read(device: &xiapi::Camera) -> Result<Self, CameraError> {
+        let get = |param: &[u8], modifier: &[u8]| -> Result<u32, CameraError> {
+            Ok(params::get_int(device, &params::info(param, modifier))?.max(1) as u32)
+        };
+        Ok(Self {
+            max_width: get(xiapi::XI_PRM_WIDTH, xiapi::XI_PRM_INFO_MAX)?,
+            max_height: get(xiapi::XI_PRM_HEIGHT, xiapi::XI_PRM_INFO_MAX)?,
+            width_inc: get(xiapi::XI_PRM_WIDTH, xiapi::XI_PRM_INFO_INCREMENT)?,
+            height_inc: get(xiapi::XI_PRM_HEIGHT, xiapi::XI_PRM_INFO_INCREMENT)?,
+            offset_x_inc: get(xiapi::XI_PRM_OFFSET_X, xiapi::XI_PRM_INFO_INCREMENT)?,
+            offset_y_inc: get(xiapi::XI_PRM_OFFSET_Y, xiapi::XI_PRM_INFO_INCREMENT)?,
+        })
+    }
+
+    fn validate(&self, roi: &xiapi::Roi) -> Result<(), CameraError> {
+        let checks = [
+            ("width", roi.width, self.width_inc),
+            ("height", roi.height, self.height_inc),
+            ("offset x", roi.offset_x, self.offset_x_inc),
+            ("offset y", roi.offset_y, self.offset_y_inc),
+        ];
+        for (name, value, increment) in checks {
+            if value % increment != 0 {
+                return Err(CameraError::InvalidRoi(format!(
+                    "{} {} is not a multiple of {}",
+                    name, value, increment
+                )));
+            }
+        }
+        if roi.offset_x + roi.width > self.max_width || roi.offset_y + roi.height > self.max_height
+        {
+            return Err(CameraError::InvalidRoi(format!(
+                "{}x{} at ({}, {}) does not fit the {}x{} sensor",
+                roi.width, roi.height, roi.offset_x, roi.offset_y, self.max_width, self.max_height
+            )));
+        }
+        Ok(())
+    }
+}
+
+/// Offset that centers `size` within `max`, rounded down to `increment`.
+fn centered_offset(max: u32, size: u32, increment: u32) -> u32 {
+    let offset = max.saturating_sub(size) / 2;
+    offset - offset % increment
+}
+
+impl CameraBackend for XimeaCamera {
+    fn configure(&mut self, config: &CameraConfig) -> anyhow::Result<()> {
+        Ok(self.apply_config(config)?)
+    }
+
+    fn start_acquisition(&mut self) -> anyhow::Result<()> {
+        match self.state.take() {
+            Some(DeviceState::Idle(device)) => {
+                let buffer = device.start_acquisition().or_xi("start acquisition")?;
+                self.state = Some(DeviceState::Acquiring(buffer));
+            }
+            other => self.state = other,
+        }
+        Ok(())
+    }
+
+    fn stop_acquisition(&mut self) -> anyhow::Result<()> {
+        match self.state.take() {
+            Some(DeviceState::Acquiring(buffer)) => {
+                let device = buffer.stop_acquisition().or_xi("stop acquisition")?;
+                self.state = Some(DeviceState::Idle(device));
+            }
+            other => self.state = other,
+        }
+        Ok(())
+    }
+
+    fn set_parameter(&mut self, name: &str, value: &str) -> anyhow::Result<()> {
+        let value: f32 = value
+            .parse()
+            .map_err(|_| anyhow::anyhow!("Invalid value {:?} for {}", value, name))?;
+        match name {
+            "exposure" | "gain" if self.auto_exposure => {
+                anyhow::bail!("{} is controlled by auto exposure", name)
+            }
+            "framerate" | "fps" if self.trigger_mode != TriggerMode::FreeRun => {
+                anyhow::bail!("Framerate is set by the external trigger")
+            }
+            _ => {}
+        }
+        let device = self.device()?;
+        match name {
+            "exposure" => device.set_exposure(value).or_xi("set exposure")?,
+            "gain" => device.set_gain(value).or_xi("set gain")?,
+            "framerate" | "fps" => device.set_framerate(value).or_xi("set framerate")?,
+            _ => anyhow::bail!("Unknown parameter {:?}", name),
+        }
+        Ok(())
+    }
+
+    fn get_parameter(&mut self, name: &str) -> anyhow::Result<f32> {
+        let device = self.device()?;
+        Ok(match name {
+            "exposure" => device.exposure().or_xi("read exposure")?,
+            "gain" => device.gain().or_xi("read gain")?,
+            "framerate" | "fps" => device.framerate().or_xi("read framerate")?,
+            _ => anyhow::bail!("Unknown parameter {:?}", name),
+        })
     }
 
-    fn stop_acquisition(&self) -> Result<()> {
-        self.device.stop_acquisition().context("Failed to stop acquisition")
+    fn applied_settings(&mut self) -> anyhow::Result<AppliedCameraSettings> {
+        Ok(XimeaCamera::applied_settings(self)?)
+    }
+
+    fn next_frame(&mut self) -> anyhow::Result<ImageData> {
+        let Some(DeviceState::Acquiring(buffer)) = self.state.as_ref() else {
+            return Err(CameraError::NotAcquiring.into());
+        };
+        let pixel_format = self.pixel_format;
+
+        // the metadata has to be read before the frame is converted
+        macro_rules! frame_metadata {
+            ($frame:expr) => {
+                ImageData {
+                    data: Vec::new(),
+                    pixel_format,
+                    width: $frame.width(),
+                    height: $frame.height(),
+                    nframe: $frame.nframe(),
+                    acq_nframe: $frame.acq_nframe(),
+                    timestamp_raw: $frame.timestamp_raw(),
+                    exposure_time: $frame.exposure_time_us(),
+                }
+            };
+        }
+
+        let image_data = match pixel_format.bytes_per_pixel() {
+            2 => {
+                let frame = buffer
+                    .next_image::<u16>(None)
+                    .or_xi("get image from camera")?;
+                let mut image_data = frame_metadata!(frame);
+                image_data.data = ImageBuffer::<Luma<u16>, Vec<u16>>::from(frame)
+                    .into_raw()
+                    .iter()
+                    .flat_map(|pixel| pixel.to_le_bytes())
+                    .collect();
+                image_data
+            }
+            3 => {
+                // three bytes per pixel do not fit the single-channel conversion
+                let frame = buffer
+                    .next_image::<u8>(None)
+                    .or_xi("get image from camera")?;
+                let mut image_data = frame_metadata!(frame);
+                image_data.data = frame.data().to_vec();
+                image_data
+            }
+            _ => {
+                let frame = buffer
+                    .next_image::<u8>(None)
+                    .or_xi("get image from camera")?;
+                let mut image_data = frame_metadata!(frame);
+                image_data.data = ImageBuffer::<Luma<u8>, Vec<u8>>::from(frame).into_raw();
+                image_data
+            }
+        };
+        Ok(image_data)
     }
 }
 
@@ -96,4 +653,4 @@ impl Drop for XimeaCamera {
         }
         info!("Closing XIMEA camera");
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,56 @@
+use serde::Serialize;
+
+use super::error::CameraError;
+use super::params;
+
+/// A camera attached to the host, as reported before it is opened.
+///
+/// `index` is the xiApi device index, which is assigned at enumeration time
+/// and may change between reboots; `serial` is the stable identifier.
+#[derive(Debug, Clone, Serialize)]
+pub struct DeviceInfo {
+    pub index: u32,
+    pub serial: String,
+    pub model: String,
+}
+
+pub fn list_devices() -> Result<Vec<DeviceInfo>, CameraError> {
+    let count = params::number_devices()?;
+    log::debug!("Found {} XIMEA device(s)", count);
+
+    (0..count)
+        .map(|index| {
+            Ok(DeviceInfo {
+                index,
+                serial: params::device_info_string(index, xiapi::XI_PRM_DEVICE_SN)?,
+                model: params::device_info_string(index, xiapi::XI_PRM_DEVICE_NAME)?,
+            })
+        })
+        .collect()
+}
+
+/// What an opened camera reports it can do, as printed by `list-cameras`.
+#[derive(Debug, Clone, Serialize)]
+pub struct CameraCapabilities {
+    pub sensor_width: u32,
+    pub sensor_height: u32,
+    pub image_formats: Vec<&'static str>,
+    /// ROI the maximum framerate was measured at
+    pub roi: (u32, u32),
+    pub max_framerate: f32,
+    pub exposure_min_us: f32,
+    pub exposure_max_us: f32,
+}
+
+/// Resolve a serial number to the device index it currently has.
+pub fn find_device(serial: &str) -> Result<DeviceInfo, CameraError> {
+    let devices = list_devices()?;
+    devices
+        .iter()
+        .find(|device| device.serial == serial)
+        .cloned()
+        .ok_or_else(|| CameraError::NotFound {
+            serial: serial.to_string(),
+            available: devices.into_iter().map(|device| device.serial).collect(),
+        })
+}
@@ -0,0 +1,50 @@
+use thiserror::Error;
+
+use crate::structs::PixelFormat;
+
+#[derive(Debug, Error)]
+pub enum CameraError {
+    #[error("failed to open XIMEA camera (xiApi error {0})")]
+    Open(i32),
+
+    #[error("failed to {action} (xiApi error {code})")]
+    Api { action: &'static str, code: i32 },
+
+    #[error("failed to access parameter `{name}` (xiApi error {code})")]
+    Param { name: String, code: i32 },
+
+    #[error("no camera with serial number {serial} (attached: {available:?})")]
+    NotFound {
+        serial: String,
+        available: Vec<String>,
+    },
+
+    #[error("no XIMEA camera attached")]
+    NoDevices,
+
+    #[error("{requested:?} does not match the camera's color filter array {actual}")]
+    ColorFilterMismatch {
+        requested: PixelFormat,
+        actual: &'static str,
+    },
+
+    #[error("invalid ROI: {0}")]
+    InvalidRoi(String),
+
+    #[error("acquisition has not been started")]
+    NotAcquiring,
+
+    #[error("camera handle was lost after a failed acquisition state change")]
+    HandleLost,
+}
+
+/// Attach a description of what was attempted to a raw xiApi return code.
+pub(crate) trait XiResultExt<T> {
+    fn or_xi(self, action: &'static str) -> Result<T, CameraError>;
+}
+
+impl<T> XiResultExt<T> for Result<T, i32> {
+    fn or_xi(self, action: &'static str) -> Result<T, CameraError> {
+        self.map_err(|code| CameraError::Api { action, code })
+    }
+}
@@ -0,0 +1,90 @@
+//! Checked wrappers around the raw `xiSetParam*` calls for parameters that
+//! xiapi does not expose as methods.
+
+use std::ffi::c_char;
+
+use super::error::CameraError;
+
+fn param_name(param: &[u8]) -> String {
+    String::from_utf8_lossy(param.strip_suffix(b"\0").unwrap_or(param)).into_owned()
+}
+
+fn check(param: &[u8], code: i32) -> Result<(), CameraError> {
+    // XI_OK
+    if code == 0 {
+        Ok(())
+    } else {
+        Err(CameraError::Param {
+            name: param_name(param),
+            code,
+        })
+    }
+}
+
+/// `param` must be one of the NUL-terminated `xiapi::XI_PRM_*` constants.
+pub(crate) fn set_int(cam: &xiapi::Camera, param: &[u8], value: i32) -> Result<(), CameraError> {
+    let code = unsafe { xiapi::xiSetParamInt(**cam, param.as_ptr() as *const c_char, value) };
+    check(param, code)
+}
+
+/// `param` must be one of the NUL-terminated `xiapi::XI_PRM_*` constants.
+pub(crate) fn set_float(cam: &xiapi::Camera, param: &[u8], value: f32) -> Result<(), CameraError> {
+    let code = unsafe { xiapi::xiSetParamFloat(**cam, param.as_ptr() as *const c_char, value) };
+    check(param, code)
+}
+
+/// Number of XIMEA devices currently attached to the host.
+pub(crate) fn number_devices() -> Result<u32, CameraError> {
+    let mut count: u32 = 0;
+    let code = unsafe { xiapi::xiGetNumberDevices(&mut count) };
+    if code == 0 {
+        Ok(count)
+    } else {
+        Err(CameraError::Api {
+            action: "enumerate devices",
+            code,
+        })
+    }
+}
+
+/// Read a string parameter of a device that has not been opened yet.
+pub(crate) fn device_info_string(device_index: u32, param: &[u8]) -> Result<String, CameraError> {
+    let mut buffer = [0u8; 256];
+    let code = unsafe {
+        xiapi::xiGetDeviceInfoString(
+            device_index,
+            param.as_ptr() as *const c_char,
+            buffer.as_mut_ptr() as *mut c_char,
+            buffer.len() as u32,
+        )
+    };
+    check(param, code)?;
+    let end = buffer.iter().position(|&b| b == 0).unwrap_or(buffer.len());
+    Ok(String::from_utf8_lossy(&buffer[..end]).trim().to_string())
+}
+
+/// `param` must be one of the NUL-terminated `xiapi::XI_PRM_*` constants.
+pub(crate) fn get_int(cam: &xiapi::Camera, param: &[u8]) -> Result<i32, CameraError> {
+    let mut value = 0;
+    let code = unsafe { xiapi::xiGetParamInt(**cam, param.as_ptr() as *const c_char, &mut value) };
+    check(param, code)?;
+    Ok(value)
+}
+
+/// `param` must be one of the NUL-terminated `xiapi::XI_PRM_*` constants.
+pub(crate) fn get_float(cam: &xiapi::Camera, param: &[u8]) -> Result<f32, CameraError> {
+    let mut value = 0.0;
+    let code =
+        unsafe { xiapi::xiGetParamFloat(**cam, param.as_ptr() as *const c_char, &mut value) };
+    check(param, code)?;
+    Ok(value)
+}
+
+/// Combine a parameter with an info modifier such as `xiapi::XI_PRM_INFO_MAX`
+/// into the NUL-terminated name xiApi expects, e.g. `width:max`.
+pub(crate) fn info(param: &[u8], modifier: &[u8]) -> Vec<u8> {
+    let mut name = param.strip_suffix(b"\0").unwrap_or(param).to_vec();
+    name.extend_from_slice(modifier.strip_suffix(b"\0").unwrap_or(modifier));
+    name.push(0);
+    name
+}